@@ -0,0 +1,83 @@
+//! Traversal of large nested documents.
+//!
+//! `walk` and `byte_size` skip each byte once, so their throughput holds steady as the
+//! input grows.  The array and map iterators measure each child as they yield it, so
+//! recursing through them skips every element once per level it is nested in: their
+//! throughput falls off with depth (see the `chain` group).
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use minimp::decode::*;
+
+/// Build a tree of fixarrays `depth` levels deep, each holding `fanout` elements
+fn tree(buf: &mut Vec<u8>, fanout: u8, depth: usize) {
+    if depth == 0 {
+        buf.push(0x01);
+    } else {
+        buf.push(0x90 + fanout);
+        for _ in 0..fanout {
+            tree(buf, fanout, depth - 1);
+        }
+    }
+}
+
+/// Build a chain of two element arrays `[1, [1, [1, ...]]]` that is `depth` levels deep
+fn chain(depth: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for _ in 0..depth {
+        buf.extend_from_slice(&[0x92, 0x01]);
+    }
+    buf.push(0x01);
+    buf
+}
+
+/// Visit every element using the array and map iterators, returning how many there were
+fn visit(el: DecodedElement) -> usize {
+    match el {
+        DecodedElement::Array(a) => 1 + a.map(visit).sum::<usize>(),
+        DecodedElement::Map(m) => 1 + m.map(|kv| visit(kv.key) + visit(kv.value)).sum::<usize>(),
+        _ => 1
+    }
+}
+
+struct Counter(usize);
+
+impl<'a> Visitor<'a> for Counter {
+    fn visit_int(&mut self, _val: i64) { self.0 += 1; }
+    fn enter_array(&mut self, _len: usize) { self.0 += 1; }
+}
+
+fn nested(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree");
+    for depth in [2, 3, 4, 5] {
+        let mut buf = Vec::new();
+        tree(&mut buf, 8, depth);
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_with_input(BenchmarkId::new("iterate", buf.len()), &buf, |b, buf| {
+            b.iter(|| visit(DecodedElement::from_slice_idx(black_box(buf), 0, false).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("walk", buf.len()), &buf, |b, buf| {
+            b.iter(|| {
+                let mut counter = Counter(0);
+                walk(black_box(buf), &mut counter, false).unwrap();
+                counter.0
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("chain");
+    for depth in [8, 16, 32, 64] {
+        let buf = chain(depth);
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_with_input(BenchmarkId::new("iterate", depth), &buf, |b, buf| {
+            b.iter(|| visit(DecodedElement::from_slice_idx(black_box(buf), 0, false).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("byte_size", depth), &buf, |b, buf| {
+            b.iter(|| DecodedElement::from_slice_idx(black_box(buf), 0, false).unwrap().byte_size())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, nested);
+criterion_main!(benches);
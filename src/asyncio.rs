@@ -0,0 +1,441 @@
+// mod asyncio
+
+use std::future::poll_fn;
+use std::io;
+use std::task::{Context, Poll};
+
+use crate::decode::{probe, DecodedElement, Probe};
+use crate::error::Error;
+
+/// The most to read at once beyond what the buffer already holds, so that a huge length in
+/// a header doesn't allocate memory before the data to fill it has arrived
+const READ_CHUNK: usize = 4096;
+
+/// The largest message the readers and the codec accept unless told otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Check the size a message needs, so far or in total, against the limit
+fn check_size(size: usize, max_message_size: usize) -> io::Result<()> {
+    if size > max_message_size {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "message is larger than max_message_size"))
+    } else {
+        Ok(())
+    }
+}
+
+/// The bytes read so far, shared by the readers for each runtime
+struct ReadBuffer {
+    buf: Vec<u8>,
+    filled: usize,
+    /// The size of the element returned last, which is dropped on the next read
+    consumed: usize,
+    local_endian_fields: bool,
+    max_message_size: usize,
+}
+
+impl ReadBuffer {
+    fn new(local_endian_fields: bool) -> Self {
+        Self{buf: Vec::new(), filled: 0, consumed: 0, local_endian_fields, max_message_size: DEFAULT_MAX_MESSAGE_SIZE}
+    }
+
+    /// Drop the element returned last and probe the next one
+    fn probe(&mut self) -> io::Result<Probe> {
+        self.buf.copy_within(self.consumed..self.filled, 0);
+        self.filled -= self.consumed;
+        self.consumed = 0;
+        let p = probe(&self.buf[..self.filled], self.local_endian_fields).map_err(io::Error::from)?;
+        let (Probe::Complete(size) | Probe::Incomplete(size)) = p;
+        check_size(size, self.max_message_size)?;
+        Ok(p)
+    }
+
+    /// Get space to read into, towards the `need` bytes the next element takes
+    fn space(&mut self, need: usize) -> &mut [u8] {
+        let want = need.min(self.filled * 2).max(self.filled + READ_CHUNK);
+        if self.buf.len() < want {
+            self.buf.resize(want, 0);
+        }
+        &mut self.buf[self.filled..]
+    }
+
+    /// Take the complete element at the front of the buffer
+    fn element(&mut self, len: usize) -> io::Result<Option<DecodedElement<'_>>> {
+        self.consumed = len;
+        match DecodedElement::from_slice_idx(&self.buf[..len], 0, self.local_endian_fields) {
+            Some(el) => Ok(Some(el)),
+            None => Err(Error::InvalidData.into())
+        }
+    }
+
+    /// Account for `n` bytes read into `space`, where 0 means the stream has ended.  The
+    /// stream may only end between elements.
+    fn advance(&mut self, n: usize) -> io::Result<bool> {
+        if n > 0 {
+            self.filled += n;
+            Ok(true)
+        } else if self.filled == 0 {
+            Ok(false)
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended inside an element"))
+        }
+    }
+
+    /// Read the next element, calling `poll_read` to fill the buffer until it is complete.
+    /// `poll_read` returns how many bytes it read, where 0 means the stream has ended.
+    async fn read<F>(&mut self, mut poll_read: F) -> io::Result<Option<DecodedElement<'_>>>
+    where F: FnMut(&mut Context<'_>, &mut [u8]) -> Poll<io::Result<usize>>
+    {
+        loop {
+            match self.probe()? {
+                Probe::Complete(len) => return self.element(len),
+                Probe::Incomplete(need) => {
+                    let space = self.space(need);
+                    let n = poll_fn(|cx| poll_read(cx, space)).await?;
+                    if !self.advance(n)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Write all of `buf`, calling `poll_write` until it has taken every byte
+async fn write_all<F>(buf: &[u8], mut poll_write: F) -> io::Result<()>
+where F: FnMut(&mut Context<'_>, &[u8]) -> Poll<io::Result<usize>>
+{
+    let mut written = 0;
+    while written < buf.len() {
+        let n = poll_fn(|cx| poll_write(cx, &buf[written..])).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+/// Readers and writers for tokio's `AsyncRead` and `AsyncWrite`, and a codec for
+/// `tokio_util::codec::Framed`
+#[cfg(feature = "tokio")]
+pub mod tokio {
+    use std::future::poll_fn;
+    use std::io;
+    use std::pin::Pin;
+
+    use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::{check_size, write_all, ReadBuffer, DEFAULT_MAX_MESSAGE_SIZE, READ_CHUNK};
+    use crate::decode::{probe, DecodedElement, Probe};
+    use crate::encode::{encode_to_bytes, encode_to_vec, Encode, EncodeOptions};
+
+    /// Reads one element at a time from an `AsyncRead`.
+    ///
+    /// Each read uses `probe` to find out how many bytes the next element needs, waits for
+    /// them, and returns the element decoded in place from the reader's buffer.  Bytes read
+    /// past the end of it are kept for the next read.
+    pub struct AsyncReader<R> {
+        inner: R,
+        buf: ReadBuffer,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncReader<R> {
+        pub fn new(inner: R, local_endian_fields: bool) -> Self {
+            Self{inner, buf: ReadBuffer::new(local_endian_fields)}
+        }
+
+        /// Fail with `InvalidData` instead of buffering a message larger than
+        /// `max_message_size`, which is `DEFAULT_MAX_MESSAGE_SIZE` to begin with
+        pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+            self.buf.max_message_size = max_message_size;
+            self
+        }
+
+        /// Read the next element, or `None` if the stream ended cleanly before it.  Fails
+        /// with `InvalidData` if the bytes aren't MessagePack, and `UnexpectedEof` if the
+        /// stream ends part way through an element.
+        pub async fn read(&mut self) -> io::Result<Option<DecodedElement<'_>>> {
+            let inner = &mut self.inner;
+            self.buf.read(|cx, space| {
+                let mut space = ReadBuf::new(space);
+                Pin::new(&mut *inner).poll_read(cx, &mut space).map_ok(|()| space.filled().len())
+            }).await
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    /// Writes elements, or anything else that implements `Encode`, to an `AsyncWrite`
+    pub struct AsyncWriter<W> {
+        inner: W,
+        buf: Vec<u8>,
+        options: EncodeOptions,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWriter<W> {
+        pub fn new(inner: W, options: EncodeOptions) -> Self {
+            Self{inner, buf: Vec::new(), options}
+        }
+
+        /// Encode `value` and write all of it.  Nothing is written if it can't be encoded.
+        pub async fn write<T: Encode + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+            self.buf.clear();
+            encode_to_vec(value, &mut self.buf, self.options).map_err(io::Error::from)?;
+            let inner = &mut self.inner;
+            write_all(&self.buf, |cx, buf| Pin::new(&mut *inner).poll_write(cx, buf)).await
+        }
+
+        pub async fn flush(&mut self) -> io::Result<()> {
+            poll_fn(|cx| Pin::new(&mut self.inner).poll_flush(cx)).await
+        }
+
+        pub fn into_inner(self) -> W {
+            self.inner
+        }
+    }
+
+    /// Splits a byte stream into elements for `tokio_util::codec::Framed`.
+    ///
+    /// Each decoded frame holds the bytes of exactly one element, ready for
+    /// `DecodedElement::from_slice_idx`.  Frames are sent by encoding anything that
+    /// implements `Encode`.
+    #[derive(Copy, Clone, Debug)]
+    pub struct MessageCodec {
+        options: EncodeOptions,
+        max_message_size: usize,
+    }
+
+    impl MessageCodec {
+        /// Create a codec that encodes with `options` and decodes with the same
+        /// `local_endian_fields`
+        pub fn new(options: EncodeOptions) -> Self {
+            Self{options, max_message_size: DEFAULT_MAX_MESSAGE_SIZE}
+        }
+
+        /// Fail with `InvalidData` instead of buffering a frame larger than
+        /// `max_message_size`, which is `DEFAULT_MAX_MESSAGE_SIZE` to begin with
+        pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+            self.max_message_size = max_message_size;
+            self
+        }
+    }
+
+    impl Default for MessageCodec {
+        fn default() -> Self {
+            Self::new(EncodeOptions::default())
+        }
+    }
+
+    impl Decoder for MessageCodec {
+        type Item = Bytes;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+            let p = probe(src, self.options.local_endian_fields).map_err(io::Error::from)?;
+            let (Probe::Complete(size) | Probe::Incomplete(size)) = p;
+            check_size(size, self.max_message_size)?;
+            match p {
+                Probe::Complete(len) => Ok(Some(src.split_to(len).freeze())),
+                Probe::Incomplete(need) => {
+                    src.reserve((need - src.len()).min(READ_CHUNK));
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    impl<T: Encode> Encoder<T> for MessageCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, item: T, dst: &mut BytesMut) -> io::Result<()> {
+            encode_to_bytes(&item, dst, self.options).map_err(io::Error::from)?;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::encode::EncodedElement;
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_util::codec::Framed;
+
+        #[::tokio::test]
+        async fn read_write_duplex() {
+            // A pipe this small makes every element arrive in pieces
+            let (client, server) = ::tokio::io::duplex(3);
+            let long = "x".repeat(1000);
+            let writer = ::tokio::spawn(async move {
+                let mut writer = AsyncWriter::new(client, EncodeOptions::default());
+                writer.write(&EncodedElement::UInt(300)).await.unwrap();
+                writer.write(long.as_str()).await.unwrap();
+                writer.write(&(true, [1.5_f64, -2.0], "end")).await.unwrap();
+                writer.flush().await.unwrap();
+            });
+            let mut reader = AsyncReader::new(server, false);
+            assert_eq!(Some(300), reader.read().await.unwrap().and_then(|el| el.as_f64()).map(|f| f as u64));
+            let Some(DecodedElement::Str{val, ..}) = reader.read().await.unwrap() else { panic!() };
+            assert_eq!(1000, val.len());
+            assert_eq!("[true, [1.5, -2.0], \"end\"]", reader.read().await.unwrap().unwrap().to_string());
+            writer.await.unwrap();
+            assert!(reader.read().await.unwrap().is_none());
+        }
+
+        #[::tokio::test]
+        async fn read_errors() {
+            let (mut client, server) = ::tokio::io::duplex(16);
+            ::tokio::io::AsyncWriteExt::write_all(&mut client, &[0x01, 0x92, 0x01]).await.unwrap();
+            drop(client);
+            let mut reader = AsyncReader::new(server, false);
+            assert!(reader.read().await.unwrap().is_some());
+            assert_eq!(io::ErrorKind::UnexpectedEof, reader.read().await.unwrap_err().kind());
+            let (mut client, server) = ::tokio::io::duplex(16);
+            ::tokio::io::AsyncWriteExt::write_all(&mut client, &[0xC1]).await.unwrap();
+            let mut reader = AsyncReader::new(server, false);
+            assert_eq!(io::ErrorKind::InvalidData, reader.read().await.unwrap_err().kind());
+        }
+
+        #[::tokio::test]
+        async fn message_size_limit() {
+            // A bin32 that claims to be 4 GiB fails before any of it is buffered
+            let (mut client, server) = ::tokio::io::duplex(16);
+            ::tokio::io::AsyncWriteExt::write_all(&mut client, &[0xC6, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]).await.unwrap();
+            let mut reader = AsyncReader::new(server, false);
+            assert_eq!(io::ErrorKind::InvalidData, reader.read().await.unwrap_err().kind());
+            // An array whose size only shows as its elements arrive
+            let (mut client, server) = ::tokio::io::duplex(64);
+            ::tokio::io::AsyncWriteExt::write_all(&mut client, &[0x91, 0x01, 0x93, 0xA3, b'a', b'b', b'c', 0xA3, b'd']).await.unwrap();
+            let mut reader = AsyncReader::new(server, false).with_max_message_size(6);
+            assert_eq!("[1]", reader.read().await.unwrap().unwrap().to_string());
+            assert_eq!(io::ErrorKind::InvalidData, reader.read().await.unwrap_err().kind());
+
+            let mut codec = MessageCodec::default().with_max_message_size(6);
+            let mut src = BytesMut::from(&[0x91, 0x01, 0xC4, 0x05][..]);
+            assert_eq!(&[0x91, 0x01], &codec.decode(&mut src).unwrap().unwrap()[..]);
+            assert_eq!(io::ErrorKind::InvalidData, codec.decode(&mut src).unwrap_err().kind());
+        }
+
+        #[::tokio::test]
+        async fn framed_codec() {
+            let (client, server) = ::tokio::io::duplex(5);
+            let options = EncodeOptions{local_endian_fields: true, ..Default::default()};
+            let mut client = Framed::new(client, MessageCodec::new(options));
+            let mut server = Framed::new(server, MessageCodec::new(options));
+            let sent = ::tokio::spawn(async move {
+                client.send(EncodedElement::Int(-70000)).await.unwrap();
+                client.send(vec!["a", "bc"]).await.unwrap();
+            });
+            let frame = server.next().await.unwrap().unwrap();
+            assert_eq!(5, frame.len());
+            assert_eq!(Some(DecodedElement::Int{val: -70000, size: 4, raw: &frame[..]}), DecodedElement::from_slice_idx(&frame, 0, true));
+            let frame = server.next().await.unwrap().unwrap();
+            assert_eq!(&[0x92, 0xA1, b'a', 0xA2, b'b', b'c'], &frame[..]);
+            sent.await.unwrap();
+            assert!(server.next().await.is_none());
+        }
+    }
+}
+
+/// Readers and writers for the `AsyncRead` and `AsyncWrite` traits of `futures-io`
+#[cfg(feature = "futures-io")]
+pub mod futures {
+    use std::future::poll_fn;
+    use std::io;
+    use std::pin::Pin;
+
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use super::{write_all, ReadBuffer};
+    use crate::decode::DecodedElement;
+    use crate::encode::{encode_to_vec, Encode, EncodeOptions};
+
+    /// Reads one element at a time from an `AsyncRead`, like `tokio::AsyncReader`
+    pub struct AsyncReader<R> {
+        inner: R,
+        buf: ReadBuffer,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncReader<R> {
+        pub fn new(inner: R, local_endian_fields: bool) -> Self {
+            Self{inner, buf: ReadBuffer::new(local_endian_fields)}
+        }
+
+        /// Fail with `InvalidData` instead of buffering a message larger than
+        /// `max_message_size`, which is `DEFAULT_MAX_MESSAGE_SIZE` to begin with
+        pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+            self.buf.max_message_size = max_message_size;
+            self
+        }
+
+        /// Read the next element, or `None` if the stream ended cleanly before it.  Fails
+        /// with `InvalidData` if the bytes aren't MessagePack, and `UnexpectedEof` if the
+        /// stream ends part way through an element.
+        pub async fn read(&mut self) -> io::Result<Option<DecodedElement<'_>>> {
+            let inner = &mut self.inner;
+            self.buf.read(|cx, space| Pin::new(&mut *inner).poll_read(cx, space)).await
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+
+    /// Writes elements, or anything else that implements `Encode`, to an `AsyncWrite`
+    pub struct AsyncWriter<W> {
+        inner: W,
+        buf: Vec<u8>,
+        options: EncodeOptions,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWriter<W> {
+        pub fn new(inner: W, options: EncodeOptions) -> Self {
+            Self{inner, buf: Vec::new(), options}
+        }
+
+        /// Encode `value` and write all of it.  Nothing is written if it can't be encoded.
+        pub async fn write<T: Encode + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+            self.buf.clear();
+            encode_to_vec(value, &mut self.buf, self.options).map_err(io::Error::from)?;
+            let inner = &mut self.inner;
+            write_all(&self.buf, |cx, buf| Pin::new(&mut *inner).poll_write(cx, buf)).await
+        }
+
+        pub async fn flush(&mut self) -> io::Result<()> {
+            poll_fn(|cx| Pin::new(&mut self.inner).poll_flush(cx)).await
+        }
+
+        pub fn into_inner(self) -> W {
+            self.inner
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::encode::EncodedElement;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        #[::tokio::test]
+        async fn read_write_duplex() {
+            let (client, server) = ::tokio::io::duplex(2);
+            let writer = ::tokio::spawn(async move {
+                let mut writer = AsyncWriter::new(client.compat(), EncodeOptions::default());
+                writer.write(&EncodedElement::Bin(&[7; 40])).await.unwrap();
+                writer.write(&Some(-1_i8)).await.unwrap();
+                writer.flush().await.unwrap();
+            });
+            let mut reader = AsyncReader::new(server.compat(), false);
+            assert_eq!(Some(&[7; 40][..]), reader.read().await.unwrap().and_then(|el| match el {
+                DecodedElement::Bin{val, ..} => Some(val),
+                _ => None
+            }));
+            assert_eq!("-1", reader.read().await.unwrap().unwrap().to_string());
+            writer.await.unwrap();
+            assert!(reader.read().await.unwrap().is_none());
+        }
+    }
+}
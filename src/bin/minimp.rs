@@ -0,0 +1,377 @@
+//! Inspect and convert MessagePack files.
+//!
+//! Every command reads a file, or standard input if the file is `-` or left out.  Files
+//! may hold several messages one after another.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+use minimp::decode::*;
+use minimp::display::dump;
+use minimp::encode::EncodedElement;
+use minimp::schema::Schema;
+use serde_json::Value;
+
+const USAGE: &str = "\
+usage: minimp <command> [args] [file]
+
+commands:
+  dump [file]                 annotated listing of every element
+  to-json [file]              print each message as a line of JSON
+  from-json [file]            encode a JSON document as MessagePack on stdout
+  validate <schema> [file]    check each message against a JSON schema file
+  get <path> [file]           print the element at a dotted path, such as items.3.name
+  stats [file]                count elements by type, and report the deepest nesting and size";
+
+/// A failed command, with the exit code it should produce
+#[derive(Debug)]
+struct Failure(u8, String);
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        Failure(2, e.to_string())
+    }
+}
+
+impl From<minimp::error::Error> for Failure {
+    fn from(e: minimp::error::Error) -> Self {
+        Failure(2, e.to_string())
+    }
+}
+
+fn read_input(path: Option<&String>) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match path.map(String::as_str) {
+        None | Some("-") => { io::stdin().read_to_end(&mut buf)?; },
+        Some(p) => buf = std::fs::read(p)?,
+    }
+    Ok(buf)
+}
+
+/// Find the start of every message in `buf`
+fn messages(buf: &[u8]) -> Result<Vec<usize>, Failure> {
+    let mut starts = Vec::new();
+    let mut idx = 0;
+    while idx < buf.len() {
+        starts.push(idx);
+        idx = skip(buf, idx, false).map_err(|e| Failure(2, format!("message at offset {}: {}", idx, e)))?;
+    }
+    Ok(starts)
+}
+
+/// Decode the message starting at `idx`.  `messages` has already skipped it, but that
+/// doesn't check strings, so it can still fail here.
+fn message(buf: &[u8], idx: usize) -> Result<DecodedElement<'_>, Failure> {
+    DecodedElement::from_slice_idx(buf, idx, false)
+        .ok_or_else(|| Failure(2, format!("message at offset {}: {}", idx, minimp::error::Error::InvalidData)))
+}
+
+/// Split a dotted path into segments, treating numbers as indexes
+fn parse_path(path: &str) -> Vec<PathSegment<'_>> {
+    path.split('.')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.parse() {
+            Ok(i) => PathSegment::Index(i),
+            Err(_) => PathSegment::Key(s),
+        })
+        .collect()
+}
+
+/// Builds a JSON value as `walk` visits a message
+#[derive(Default)]
+struct JsonBuilder {
+    // Containers still being filled in, each with the key waiting for its value (for
+    // maps) and whether the next element is a key
+    stack: Vec<(Value, Option<String>, bool)>,
+    done: Option<Value>,
+}
+
+impl JsonBuilder {
+    fn push(&mut self, v: Value) {
+        match self.stack.last_mut() {
+            None => self.done = Some(v),
+            Some((Value::Array(a), _, _)) => a.push(v),
+            Some((Value::Object(_), key, true)) => *key = Some(match v {
+                Value::String(s) => s,
+                other => other.to_string(),
+            }),
+            Some((Value::Object(o), key, false)) => { o.insert(key.take().unwrap_or_default(), v); },
+            Some(_) => unreachable!(),
+        }
+    }
+    fn bytes(data: &[u8]) -> Value {
+        Value::Array(data.iter().map(|b| Value::from(*b)).collect())
+    }
+    fn exit(&mut self) {
+        if let Some((v, _, _)) = self.stack.pop() {
+            self.push(v);
+        }
+    }
+}
+
+impl<'a> Visitor<'a> for JsonBuilder {
+    fn visit_nil(&mut self) { self.push(Value::Null); }
+    fn visit_int(&mut self, val: i64) { self.push(val.into()); }
+    fn visit_uint(&mut self, val: u64) { self.push(val.into()); }
+    fn visit_bool(&mut self, val: bool) { self.push(val.into()); }
+    fn visit_float(&mut self, val: f32) { self.push(val.into()); }
+    fn visit_double(&mut self, val: f64) { self.push(val.into()); }
+    fn visit_str(&mut self, val: &'a str) { self.push(val.into()); }
+    fn visit_bin(&mut self, val: &'a [u8]) { self.push(Self::bytes(val)); }
+    fn visit_ext(&mut self, exttype: u8, data: &'a [u8]) {
+        let mut o = serde_json::Map::new();
+        o.insert("ext".into(), exttype.into());
+        o.insert("data".into(), Self::bytes(data));
+        self.push(Value::Object(o));
+    }
+    fn enter_array(&mut self, len: usize) { self.stack.push((Value::Array(Vec::with_capacity(len)), None, false)); }
+    fn exit_array(&mut self) { self.exit(); }
+    fn enter_map(&mut self, _len: usize) { self.stack.push((Value::Object(serde_json::Map::new()), None, false)); }
+    fn map_key(&mut self) {
+        if let Some(top) = self.stack.last_mut() {
+            top.2 = true;
+        }
+    }
+    fn map_value(&mut self) {
+        if let Some(top) = self.stack.last_mut() {
+            top.2 = false;
+        }
+    }
+    fn exit_map(&mut self) { self.exit(); }
+}
+
+/// Convert the message at the start of `buf` to JSON.
+///
+/// Bin becomes an array of byte values and ext an object with `ext` and `data`.  Map keys
+/// that aren't strings are written out as JSON text.
+fn to_json(buf: &[u8]) -> Result<Value, Failure> {
+    let mut builder = JsonBuilder::default();
+    walk(buf, &mut builder, false)?;
+    builder.done.ok_or_else(|| Failure(2, "empty message".into()))
+}
+
+/// Encode `el` into a buffer of at most `max_size` bytes
+fn encode(el: EncodedElement, max_size: usize) -> Result<Vec<u8>, Failure> {
+    let mut buf = vec![0_u8; max_size];
+    let n = el.write_to(&mut buf, 0, false);
+    if n == 0 {
+        return Err(Failure(2, format!("cannot encode {:?}", el)));
+    }
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Encode a JSON value as MessagePack
+fn from_json(v: &Value) -> Result<Vec<u8>, Failure> {
+    match v {
+        Value::Null => encode(EncodedElement::Nil, 1),
+        Value::Bool(b) => encode(EncodedElement::Bool(*b), 1),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                encode(EncodedElement::Int(i), 9)
+            } else if let Some(u) = n.as_u64() {
+                encode(EncodedElement::UInt(u), 9)
+            } else {
+                encode(EncodedElement::Double(n.as_f64().unwrap_or(f64::NAN)), 9)
+            }
+        },
+        Value::String(s) => encode(EncodedElement::Str(s), s.len() + 5),
+        Value::Array(items) => {
+            // Encode the elements first, then splice them in after the header
+            let encoded = items.iter().map(from_json).collect::<Result<Vec<_>, _>>()?;
+            let raw: Vec<EncodedElement> = encoded.iter().map(|e| EncodedElement::Raw(e)).collect();
+            encode(EncodedElement::Array(&raw), encoded.iter().map(Vec::len).sum::<usize>() + 5)
+        },
+        Value::Object(o) => {
+            let encoded = o.iter()
+                .map(|(k, v)| Ok([encode(EncodedElement::Str(k), k.len() + 5)?, from_json(v)?]))
+                .collect::<Result<Vec<_>, Failure>>()?;
+            let raw: Vec<[EncodedElement; 2]> = encoded.iter()
+                .map(|[k, v]| [EncodedElement::Raw(k), EncodedElement::Raw(v)])
+                .collect();
+            encode(EncodedElement::Map(&raw), encoded.iter().map(|[k, v]| k.len() + v.len()).sum::<usize>() + 5)
+        },
+    }
+}
+
+/// Counts the elements of messages as `walk` visits them
+#[derive(Default)]
+struct Stats {
+    types: BTreeMap<&'static str, usize>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<'a> Visitor<'a> for Stats {
+    fn visit_element(&mut self, _idx: usize, el: &DecodedElement<'a>) {
+        *self.types.entry(el.type_name()).or_default() += 1;
+    }
+    fn enter_array(&mut self, _len: usize) { self.enter(); }
+    fn exit_array(&mut self) { self.depth -= 1; }
+    fn enter_map(&mut self, _len: usize) { self.enter(); }
+    fn exit_map(&mut self) { self.depth -= 1; }
+}
+
+impl Stats {
+    fn enter(&mut self) {
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+}
+
+fn run(args: &[String], out: &mut impl Write) -> Result<(), Failure> {
+    let usage = || Failure(2, USAGE.into());
+    let command = args.first().ok_or_else(usage)?;
+    match command.as_str() {
+        "dump" => {
+            let buf = read_input(args.get(1))?;
+            for idx in messages(&buf)? {
+                if idx > 0 {
+                    writeln!(out, "-- message at offset {:08x}", idx)?;
+                }
+                let mut text = String::new();
+                dump(&buf[idx..], &mut text, false)?;
+                write!(out, "{}", text)?;
+            }
+        },
+        "to-json" => {
+            let buf = read_input(args.get(1))?;
+            for idx in messages(&buf)? {
+                writeln!(out, "{}", to_json(&buf[idx..])?)?;
+            }
+        },
+        "from-json" => {
+            let text = read_input(args.get(1))?;
+            let v: Value = serde_json::from_slice(&text).map_err(|e| Failure(2, e.to_string()))?;
+            out.write_all(&from_json(&v)?)?;
+        },
+        "validate" => {
+            let schema_doc = std::fs::read_to_string(args.get(1).ok_or_else(usage)?)?;
+            let schema = Schema::from_json(&schema_doc)?;
+            let buf = read_input(args.get(2))?;
+            let mut valid = true;
+            for idx in messages(&buf)? {
+                if let Err(errors) = schema.validate(message(&buf, idx)?) {
+                    valid = false;
+                    for e in errors {
+                        writeln!(out, "message at offset {}: {}", idx, e)?;
+                    }
+                }
+            }
+            if !valid {
+                return Err(Failure(1, "validation failed".into()));
+            }
+        },
+        "get" => {
+            let path = parse_path(args.get(1).ok_or_else(usage)?);
+            let buf = read_input(args.get(2))?;
+            for idx in messages(&buf)? {
+                message(&buf, idx)?;
+                match DecodedElement::locate(&buf, idx, &path, false) {
+                    Some((el, _)) => writeln!(out, "{:#}", el)?,
+                    None => return Err(Failure(1, format!("message at offset {}: no element at {}", idx, args[1]))),
+                }
+            }
+        },
+        "stats" => {
+            let buf = read_input(args.get(1))?;
+            let mut stats = Stats::default();
+            let starts = messages(&buf)?;
+            for idx in &starts {
+                walk(&buf[*idx..], &mut stats, false)?;
+            }
+            writeln!(out, "messages: {}", starts.len())?;
+            writeln!(out, "bytes: {}", buf.len())?;
+            writeln!(out, "max depth: {}", stats.max_depth)?;
+            for (name, count) in &stats.types {
+                writeln!(out, "{}: {}", name, count)?;
+            }
+        },
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args, &mut io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure(code, msg)) => {
+            eprintln!("minimp: {}", msg);
+            ExitCode::from(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let v: Value = serde_json::from_str(r#"{"id": 7, "tags": ["a", -3, 1.5, null, true]}"#).unwrap();
+        let encoded = from_json(&v).unwrap();
+        assert_eq!(v, to_json(&encoded).unwrap());
+    }
+
+    /// Write `contents` to a file for a test, and return its path
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("minimp-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Run a command, returning its exit code and what it wrote
+    fn run_args(args: &[&str]) -> (u8, String) {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let mut out = Vec::new();
+        let code = match run(&args, &mut out) {
+            Ok(()) => 0,
+            Err(Failure(code, _)) => code,
+        };
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn commands() {
+        // {"id": 7, "name": "bob"} then {"id": -1}
+        let msgs = temp_file("commands.mp", &[0x82, 0xA2, b'i', b'd', 0x07, 0xA4, b'n', b'a', b'm', b'e', 0xA3, b'b', b'o', b'b',
+                                              0x81, 0xA2, b'i', b'd', 0xFF]);
+        let schema = temp_file("commands.json", br#"{"type": "object", "properties": {"id": {"type": "integer", "minimum": 0}}}"#);
+
+        let (code, out) = run_args(&["dump", &msgs]);
+        assert_eq!(0, code);
+        assert!(out.contains("-- message at offset 0000000e"), "{}", out);
+        assert_eq!((0, "{\"id\":7,\"name\":\"bob\"}\n{\"id\":-1}\n".into()), run_args(&["to-json", &msgs]));
+        assert_eq!((1, "message at offset 14: $.id: value out of range\n".into()), run_args(&["validate", &schema, &msgs]));
+        assert_eq!((0, "7\n-1\n".into()), run_args(&["get", "id", &msgs]));
+        assert_eq!((1, "\"bob\"\n".into()), run_args(&["get", "name", &msgs]));
+        assert_eq!((0, "messages: 2\nbytes: 19\nmax depth: 1\nint: 2\nmap: 2\nstr: 4\n".into()), run_args(&["stats", &msgs]));
+        assert_eq!(2, run_args(&["frobnicate"]).0);
+        assert_eq!(2, run_args(&["validate"]).0);
+    }
+
+    #[test]
+    fn bad_input() {
+        // A str that isn't valid UTF-8 gets past `skip`, but not past decoding
+        let bad = temp_file("bad.mp", &[0xA1, 0xFF]);
+        let schema = temp_file("bad.json", br#"{"type": "string"}"#);
+        assert_eq!(2, run_args(&["validate", &schema, &bad]).0);
+        assert_eq!(2, run_args(&["dump", &bad]).0);
+        assert_eq!(2, run_args(&["get", "", &bad]).0);
+        assert_eq!(2, run_args(&["to-json", &bad]).0);
+        assert_eq!(2, run_args(&["stats", &bad]).0);
+        // Truncated input is caught before any command looks at it
+        let truncated = temp_file("truncated.mp", &[0x92, 0x01]);
+        for command in ["dump", "to-json", "stats"] {
+            assert_eq!((2, String::new()), run_args(&[command, &truncated]), "{}", command);
+        }
+    }
+
+    #[test]
+    fn path_segments() {
+        assert_eq!(vec![PathSegment::Key("items"), PathSegment::Index(3), PathSegment::Key("name")],
+                   parse_path("items.3.name"));
+        assert!(parse_path("").is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+// mod chunked
+
+use crate::encode::{write_bin_header, write_str_header};
+use crate::endian::ByteOrder;
+use crate::error::{Error, Result};
+
+/// The largest str or bin header: a marker and a 32-bit length
+pub const MAX_HEADER_SIZE: usize = 5;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BodyKind {
+    Str,
+    Bin,
+}
+
+/// The header of a str or bin, for a body that is handled a chunk at a time rather than
+/// held in memory all at once.
+///
+/// Str bodies are treated as bytes: nothing here checks that they are UTF-8, since a
+/// chunk boundary can fall inside a character.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BodyHeader {
+    pub kind: BodyKind,
+    /// The size of the header itself, including the marker
+    pub header_size: usize,
+    /// The length of the body that follows the header
+    pub len: usize,
+}
+
+impl BodyHeader {
+    /// Get the header size that a str or bin with this marker has, or `None` if the marker
+    /// begins anything else
+    pub fn size_for_marker(marker: u8) -> Option<usize> {
+        match marker {
+            0xA0..=0xBF => Some(1),
+            0xC4 | 0xD9 => Some(2),
+            0xC5 | 0xDA => Some(3),
+            0xC6 | 0xDB => Some(5),
+            _ => None
+        }
+    }
+
+    /// Read the header at the start of `buf`.  Fails with `Error::TypeMismatch` if it isn't
+    /// a str or bin, and `Error::InvalidData` if it is truncated.
+    pub fn read(buf: &[u8]) -> Result<Self> {
+        let marker = *buf.first().ok_or(Error::InvalidData)?;
+        let kind = match marker {
+            0xC4..=0xC6 => BodyKind::Bin,
+            _ => BodyKind::Str,
+        };
+        let header_size = Self::size_for_marker(marker).ok_or(Error::TypeMismatch)?;
+        let len = match header_size {
+            1 => Some((marker & 0x1F) as usize),
+            2 => ByteOrder::Big.read::<u8>(buf, 1).map(usize::from),
+            3 => ByteOrder::Big.read::<u16>(buf, 1).map(usize::from),
+            _ => ByteOrder::Big.read::<u32>(buf, 1).map(|l| l as usize),
+        }.ok_or(Error::InvalidData)?;
+        Ok(Self{kind, header_size, len})
+    }
+
+    /// Write the header for a body of `len` bytes into `slice` beginning at `idx`,
+    /// returning the number of bytes written
+    pub fn write(kind: BodyKind, len: usize, slice: &mut [u8], idx: usize) -> Result<usize> {
+        match kind {
+            BodyKind::Str => write_str_header(slice, idx, len),
+            BodyKind::Bin => write_bin_header(slice, idx, len),
+        }
+    }
+}
+
+/// Picks the body of a str or bin out of input that arrives in pieces, for sources that
+/// aren't a `std::io::Read`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BodyChunks {
+    remaining: usize,
+}
+
+impl BodyChunks {
+    pub fn new(header: BodyHeader) -> Self {
+        Self{remaining: header.len}
+    }
+
+    /// Split `input` into the part that belongs to the body and whatever follows it
+    pub fn take<'a>(&mut self, input: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        let (chunk, rest) = input.split_at(input.len().min(self.remaining));
+        self.remaining -= chunk.len();
+        (chunk, rest)
+    }
+
+    /// The number of body bytes still to come
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Check that the whole body has arrived, once the input has ended
+    pub fn finish(&self) -> Result<()> {
+        match self.remaining {
+            0 => Ok(()),
+            _ => Err(Error::InvalidData)
+        }
+    }
+}
+
+/// Writes a str or bin body through `std::io::Write` after writing its header.
+///
+/// The body must add up to exactly the length given up front: writing past it fails, and
+/// so does `finish` if it falls short.
+#[cfg(feature = "std")]
+pub struct BodyWriter<W> {
+    inner: W,
+    remaining: usize,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> BodyWriter<W> {
+    /// Write the header for a body of `len` bytes
+    pub fn new(mut inner: W, kind: BodyKind, len: usize) -> std::io::Result<Self> {
+        let mut header = [0_u8; MAX_HEADER_SIZE];
+        let n = BodyHeader::write(kind, len, &mut header, 0)?;
+        inner.write_all(&header[..n])?;
+        Ok(Self{inner, remaining: len})
+    }
+
+    /// The number of body bytes still to write
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Check that the whole body was written, and return the writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self.remaining {
+            0 => Ok(self.inner),
+            n => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("body is {} bytes short", n)))
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> std::io::Write for BodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() > self.remaining {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "body is longer than its header says"));
+        }
+        let n = self.inner.write(buf)?;
+        self.remaining -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads a str or bin body through `std::io::Read`, after reading its header.
+///
+/// Nothing past the end of the body is read from the inner reader, so it is left at the
+/// next element.  If the inner reader ends before the body does, reading fails with
+/// `UnexpectedEof`.
+#[cfg(feature = "std")]
+pub struct BodyReader<R> {
+    inner: R,
+    header: BodyHeader,
+    remaining: usize,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> BodyReader<R> {
+    /// Read a str or bin header, and nothing after it
+    pub fn new(mut inner: R) -> std::io::Result<Self> {
+        let mut buf = [0_u8; MAX_HEADER_SIZE];
+        inner.read_exact(&mut buf[..1])?;
+        let size = BodyHeader::size_for_marker(buf[0]).ok_or(Error::TypeMismatch)?;
+        inner.read_exact(&mut buf[1..size])?;
+        let header = BodyHeader::read(&buf[..size])?;
+        Ok(Self{inner, header, remaining: header.len})
+    }
+
+    pub fn header(&self) -> BodyHeader {
+        self.header
+    }
+
+    /// The number of body bytes still to read
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> std::io::Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let want = buf.len().min(self.remaining);
+        if want == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("body ended {} bytes short", self.remaining)));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::DecodedElement;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn headers() {
+        let mut buf = [0_u8; MAX_HEADER_SIZE];
+        for (kind, len, marker, size) in [(BodyKind::Str, 31, 0xBF, 1), (BodyKind::Str, 32, 0xD9, 2), (BodyKind::Bin, 0, 0xC4, 2),
+                                          (BodyKind::Bin, 256, 0xC5, 3), (BodyKind::Bin, 70000, 0xC6, 5)] {
+            assert_eq!(Ok(size), BodyHeader::write(kind, len, &mut buf, 0));
+            assert_eq!(marker, buf[0]);
+            assert_eq!(Ok(BodyHeader{kind, header_size: size, len}), BodyHeader::read(&buf[..size]));
+        }
+        assert_eq!(Err(Error::InvalidData), BodyHeader::read(&[0xC6, 0x00, 0x01]));
+        assert_eq!(Err(Error::TypeMismatch), BodyHeader::read(&[0x90]));
+    }
+
+    #[test]
+    fn stream_body() {
+        let image: Vec<u8> = (0..70000_u32).map(|i| i as u8).collect();
+        let mut writer = BodyWriter::new(Vec::new(), BodyKind::Bin, image.len()).unwrap();
+        for chunk in image.chunks(4096) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert!(writer.write(&[0]).is_err());
+        let mut out = writer.finish().unwrap();
+        out.push(0xC0);
+        assert_eq!(Some(DecodedElement::Bin{header_size: 4, val: &image, raw: &out[..70005]}), DecodedElement::from_slice_idx(&out, 0, false));
+
+        // The reader stops at the end of the body, before the nil that follows it
+        let mut reader = BodyReader::new(&out[..]).unwrap();
+        assert_eq!(BodyHeader{kind: BodyKind::Bin, header_size: 5, len: 70000}, reader.header());
+        let mut body = Vec::new();
+        let mut chunk = [0_u8; 1000];
+        loop {
+            match reader.read(&mut chunk).unwrap() {
+                0 => break,
+                n => body.extend_from_slice(&chunk[..n]),
+            }
+        }
+        assert_eq!(image, body);
+        assert_eq!(&[0xC0], reader.into_inner());
+
+        // The same body, picked out of input that arrives in uneven pieces
+        let header = BodyHeader::read(&out).unwrap();
+        let mut chunks = BodyChunks::new(header);
+        let mut body = Vec::new();
+        let mut rest: &[u8] = &[];
+        for piece in out[header.header_size..].chunks(999) {
+            let (chunk, after) = chunks.take(piece);
+            body.extend_from_slice(chunk);
+            rest = after;
+        }
+        assert_eq!(Ok(()), chunks.finish());
+        assert_eq!(image, body);
+        assert_eq!(&[0xC0], rest);
+    }
+
+    #[test]
+    fn wrong_length() {
+        let writer = BodyWriter::new(Vec::new(), BodyKind::Str, 10).unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidInput, writer.finish().unwrap_err().kind());
+        let mut reader = BodyReader::new(&[0xA3, b'a', b'b'][..]).unwrap();
+        let mut buf = [0_u8; 8];
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, reader.read(&mut buf).unwrap_err().kind());
+        let mut chunks = BodyChunks::new(BodyHeader::read(&[0xA3]).unwrap());
+        chunks.take(b"ab");
+        assert_eq!(Err(Error::InvalidData), chunks.finish());
+        assert_eq!(std::io::ErrorKind::InvalidData, BodyReader::new(&[0x01][..]).err().unwrap().kind());
+    }
+}
@@ -0,0 +1,235 @@
+// mod compress
+
+use alloc::vec::Vec;
+
+use crate::decode::{DecodedElement, MAX_DEPTH};
+use crate::encode::{encode_to_vec, Encode, EncodeOptions, EncodedElement};
+use crate::endian::ByteOrder;
+use crate::error::{Error, Result};
+
+/// The ext type for an LZ4 block, the same one other MessagePack libraries use
+pub const LZ4_EXT_TYPE: u8 = 99;
+/// The ext type for a zstd frame.  There is no common convention for this one.
+pub const ZSTD_EXT_TYPE: u8 = 100;
+
+/// How a compressed ext was compressed.
+///
+/// The ext holds the uncompressed size as a MessagePack int32, followed by the compressed
+/// bytes, which decompress to exactly one element.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Compression {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    pub fn ext_type(self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => LZ4_EXT_TYPE,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => ZSTD_EXT_TYPE,
+        }
+    }
+
+    /// Get the compression an ext type stands for, or `None` if it isn't a compressed ext
+    /// that was enabled for this build
+    pub fn from_ext_type(exttype: u8) -> Option<Self> {
+        match exttype {
+            #[cfg(feature = "lz4")]
+            LZ4_EXT_TYPE => Some(Self::Lz4),
+            #[cfg(feature = "zstd")]
+            ZSTD_EXT_TYPE => Some(Self::Zstd),
+            _ => None
+        }
+    }
+
+    /// Compress `raw` into the data of an ext
+    pub fn compress(self, raw: &[u8]) -> Result<Vec<u8>> {
+        let size = i32::try_from(raw.len()).map_err(|_| Error::LengthOverflow)?;
+        let mut data = Vec::from([0xD2]);
+        data.extend_from_slice(&ByteOrder::Big.bytes(size as u32));
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => data.extend_from_slice(&lz4_flex::block::compress(raw)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => data.extend_from_slice(&zstd::bulk::compress(raw, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|_| Error::InvalidData)?),
+        }
+        Ok(data)
+    }
+
+    /// Decompress the data of an ext.  Fails with `Error::CapacityExceeded` if it would be
+    /// larger than `max_size`, and `Error::InvalidData` if it is corrupt or doesn't
+    /// decompress to the size it says it does.
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        // Other writers may shorten the int32, so take the size in any form of int
+        let prefix = DecodedElement::from_slice_idx(data, 0, false).ok_or(Error::InvalidData)?;
+        let size = match prefix {
+            DecodedElement::Int{val, ..} => usize::try_from(val).map_err(|_| Error::InvalidData)?,
+            DecodedElement::UInt{val, ..} => usize::try_from(val).map_err(|_| Error::InvalidData)?,
+            _ => return Err(Error::InvalidData)
+        };
+        if size > max_size {
+            return Err(Error::CapacityExceeded);
+        }
+        let compressed = &data[prefix.byte_size()..];
+        let raw = match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => lz4_flex::block::decompress(compressed, size).map_err(|_| Error::InvalidData)?,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::decompress(compressed, size).map_err(|_| Error::InvalidData)?,
+        };
+        match raw.len() == size {
+            true => Ok(raw),
+            false => Err(Error::InvalidData)
+        }
+    }
+}
+
+/// A value encoded as a compressed ext, to be read back with `inflate`.
+///
+/// The value is encoded and compressed once, in `new`, so writing the ext again, or
+/// retrying into a bigger buffer after `Error::BufferTooSmall`, doesn't compress it again.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Compressed {
+    compression: Compression,
+    data: Vec<u8>,
+}
+
+impl Compressed {
+    /// Encode `value` with `options` and compress it
+    pub fn new<T: Encode + ?Sized>(compression: Compression, value: &T, options: EncodeOptions) -> Result<Self> {
+        let mut raw = Vec::new();
+        encode_to_vec(value, &mut raw, options)?;
+        Ok(Self{compression, data: compression.compress(&raw)?})
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Get the data of the ext: the uncompressed size followed by the compressed bytes
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Encode for Compressed {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        EncodedElement::Ext{exttype: self.compression.ext_type(), data: &self.data}.try_write(slice, idx, options)
+    }
+}
+
+/// Copy the element at `idx` in `buf`, replacing every compressed ext inside it with the
+/// element it decompresses to, so that it can be read with `DecodedElement` as if nothing
+/// had been compressed.
+///
+/// Compressed exts inside compressed exts are expanded as well.  Fails with
+/// `Error::CapacityExceeded` if the result would be larger than `max_size`, which keeps a
+/// small input from expanding without limit, and `Error::TooDeep` if containers and
+/// compressed exts together are nested more deeply than `decode::MAX_DEPTH`.
+pub fn inflate(buf: &[u8], idx: usize, local_endian_fields: bool, max_size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    inflate_into(buf, idx, local_endian_fields, &mut out, max_size, 0)?;
+    Ok(out)
+}
+
+/// Append the inflated element at `idx` to `out`, returning the index after it in `buf`
+fn inflate_into(buf: &[u8], idx: usize, local_endian_fields: bool, out: &mut Vec<u8>, max_size: usize, depth: usize) -> Result<usize> {
+    let el = DecodedElement::from_slice_idx(buf, idx, local_endian_fields).ok_or(Error::InvalidData)?;
+    let (copy_len, children) = match &el {
+        DecodedElement::Array(a) => (1 + a.header_size() as usize, a.len()),
+        DecodedElement::Map(m) => (1 + m.header_size() as usize, m.len().checked_mul(2).ok_or(Error::InvalidData)?),
+        DecodedElement::Ext{exttype, data, ..} => match Compression::from_ext_type(*exttype) {
+            Some(compression) => {
+                if depth == MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                let raw = compression.decompress(data, max_size - out.len())?;
+                if inflate_into(&raw, 0, local_endian_fields, out, max_size, depth + 1)? != raw.len() {
+                    return Err(Error::InvalidData);
+                }
+                return Ok(idx + el.byte_size());
+            },
+            None => (el.byte_size(), 0)
+        },
+        _ => (el.byte_size(), 0)
+    };
+    if out.len() + copy_len > max_size {
+        return Err(Error::CapacityExceeded);
+    }
+    out.extend_from_slice(&buf[idx..idx + copy_len]);
+    let mut next = idx + copy_len;
+    if children > 0 {
+        if depth == MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        for _ in 0..children {
+            next = inflate_into(buf, next, local_endian_fields, out, max_size, depth + 1)?;
+        }
+    }
+    Ok(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::from_slice;
+
+    fn compressions() -> Vec<Compression> {
+        Vec::from([
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ])
+    }
+
+    #[test]
+    fn nested_round_trip() {
+        let readings: Vec<u32> = (0..1000).map(|i| i % 17).collect();
+        for compression in compressions() {
+            let options = EncodeOptions::default();
+            let batch = ("batch", Compressed::new(compression, &readings[..], options).unwrap());
+            let value = ("sensor-1", Compressed::new(compression, &batch, options).unwrap(), 3);
+            let mut buf = Vec::new();
+            let n = encode_to_vec(&value, &mut buf, options).unwrap();
+            assert!(n < 500);
+            let DecodedElement::Array(mut a) = DecodedElement::from_slice_idx(&buf, 0, false).unwrap() else { panic!() };
+            assert!(matches!(a.get_element(1), Some(DecodedElement::Ext{exttype, ..}) if exttype == compression.ext_type()));
+
+            let doc = inflate(&buf, 0, false, 10_000).unwrap();
+            let (name, (batch, decoded), count): (&str, (&str, Vec<u32>), u8) = from_slice(&doc, false).unwrap();
+            assert_eq!(("sensor-1", "batch", 3), (name, batch, count));
+            assert_eq!(readings, decoded);
+            assert_eq!(Err(Error::CapacityExceeded), inflate(&buf, 0, false, 1000));
+        }
+    }
+
+    #[test]
+    fn other_exts_are_copied() {
+        let mut buf = Vec::new();
+        encode_to_vec(&[EncodedElement::Ext{exttype: 5, data: b"abc"}, EncodedElement::Nil], &mut buf, EncodeOptions::default()).unwrap();
+        assert_eq!(buf, inflate(&buf, 0, false, 100).unwrap());
+    }
+
+    #[test]
+    fn corrupt_data() {
+        for compression in compressions() {
+            let mut data = compression.compress(&[0x93, 1, 2, 3]).unwrap();
+            assert_eq!(&[0xD2, 0, 0, 0, 4], &data[..5]);
+            assert_eq!(Ok(Vec::from([0x93, 1, 2, 3])), compression.decompress(&data, 4));
+            assert_eq!(Err(Error::CapacityExceeded), compression.decompress(&data, 3));
+            // The size says there is more than there is
+            data[4] = 5;
+            assert_eq!(Err(Error::InvalidData), compression.decompress(&data, 100));
+            // Decompresses fine, but isn't one complete element
+            let data = compression.compress(&[0x93, 1, 2]).unwrap();
+            let mut buf = [0_u8; 64];
+            let n = EncodedElement::Ext{exttype: compression.ext_type(), data: &data}.try_write(&mut buf, 0, EncodeOptions::default()).unwrap();
+            assert_eq!(Err(Error::InvalidData), inflate(&buf[..n], 0, false, 100));
+        }
+    }
+}
@@ -3,16 +3,29 @@
 use core::iter::Iterator;
 use core::str;
 use super::bytesize::*;
+use super::endian::*;
+use super::error::*;
 
+/// Deepest nesting of arrays and maps that `walk` will follow
+pub const MAX_DEPTH: usize = 128;
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+
+/// The elements of an array, decoded one at a time as it is iterated.
+///
+/// Each element is measured as it is yielded, which means skipping over everything nested
+/// inside it.  Recursing through nested arrays and maps with the iterators therefore reads
+/// each byte once for every level it is nested in: the time taken grows with the size of
+/// the message times its depth.  `walk` visits a whole message in a single pass.
+#[derive(Copy, Clone, PartialEq)]
 pub struct ArrayDecoder<'a> {
     header_size: UBytes, // Does not include first byte
     local_endian_fields: bool,
     array: &'a [u8], // First element of this needs to be the first data byte
+    raw: &'a [u8], // Starts at the header byte of the array
     elements: usize,
-    element_size: Option<usize>,
+    size: Option<usize>, // Total size in bytes, once it is known
     next_element: usize,
+    next_idx: usize,
     eob: bool,
 }
 
@@ -22,61 +35,64 @@ impl<'a> ArrayDecoder<'a> {
     fn get_at_idx(&self, idx: usize) -> Option<DecodedElement<'a>> {
         DecodedElement::from_slice_idx(self.array, idx, self.local_endian_fields)
     }
-    /// Get the array index from the element index
-    fn idx_from_element(&mut self, element: usize) -> Option<usize> {
-        // First, we need to calculate the element size if it hasn't been done yet
-        let elsize;
-        if let Some(s) = &self.element_size {
-            elsize = *s;
-        } else {
-            if let Some(s) = self.get_at_idx(0) {
-                elsize = s.byte_size();
-                self.element_size = Some(elsize);
-            } else {
-                return None;
-            };
-        }
-        // Next, check and see if this element is in bounds
-        if element >= self.elements {
-            return None;
-        }
-        // Use the element size to calculate the index
-        let start_idx = elsize * element;
-        if start_idx >= self.array.len() {
-            // This isn't valid and we should not return anything, and mark eob
-            self.eob = true;
-            None
-        } else {
-            Some(start_idx)
-        }
-    }
     /// Get a specific element from the array
+    ///
+    /// Elements are not all the same size, so this has to walk the array from the start.
     #[inline]
     pub fn get_element(&mut self, element: usize) -> Option<DecodedElement<'a>> {
-        if element >= self.elements {
-            None
-        } else if let Some(idx) = &self.idx_from_element(element) {
-            self.get_at_idx(*idx)
-        } else {
-            None
-        }
+        let mut new_self = *self;
+        new_self.reset();
+        new_self.nth(element)
+    }
+    /// Get the number of elements in the array
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.elements
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.elements == 0
+    }
+    /// Get the size of the length field in the header, not including the first byte
+    #[inline]
+    pub fn header_size(&self) -> UBytes {
+        self.header_size
     }
     /// Reset the "next" element to the beginning
     #[inline]
-    pub fn reset(&mut self) -> () {
+    pub fn reset(&mut self) {
         self.next_element = 0;
+        self.next_idx = 0;
         self.eob = false;
     }
 
-    pub fn byte_size(&self) -> usize {
-        // Clone ourselves and iterate over the clone
-        let mut new_self = self.clone();
-        new_self.reset();
-        let mut data_size = 0;
-        for element in new_self {
-            data_size += element.byte_size();
+    /// Get the total size of the array.
+    ///
+    /// The first call skips over the array with `skip` unless it has already been iterated
+    /// to the end, and stores the result so later calls don't have to.  That is why this
+    /// takes `&mut self`; `DecodedElement::byte_size` measures without storing.
+    pub fn byte_size(&mut self) -> usize {
+        let s = self.known_size();
+        self.size = Some(s);
+        s
+    }
+    /// Get the total size without storing it
+    fn known_size(&self) -> usize {
+        if let Some(s) = self.size {
+            return s;
         }
-        data_size + self.header_size as usize + 1
+        // If the array is truncated, this is the size of what is there
+        skip_partial(self.raw, 0, self.local_endian_fields).0
+    }
+    /// Get the bytes this array was decoded from, including the header
+    #[inline]
+    pub fn raw_bytes(&self) -> &'a [u8] {
+        &self.raw[..self.known_size()]
+    }
+    /// Get the buffer from the start of the array onwards, and how it was decoded
+    #[inline]
+    pub(crate) fn source(&self) -> (&'a [u8], bool) {
+        (self.raw, self.local_endian_fields)
     }
 }
 
@@ -85,19 +101,31 @@ impl<'a> Iterator for ArrayDecoder<'a> {
     type Item = DecodedElement<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next_element < self.elements {
-            self.next_element += 1;
-            self.get_element(self.next_element)
+        // Elements are not all the same size, so walk them in order rather than
+        // going through `get_element`
+        if self.next_element < self.elements && !self.eob {
+            if let Some(mut el) = self.get_at_idx(self.next_idx) {
+                self.next_idx += el.measure();
+                self.next_element += 1;
+                if self.next_element == self.elements {
+                    // Reached the end, so now we know how big the array is
+                    self.size = Some(self.next_idx + self.header_size as usize + 1);
+                }
+                Some(el)
+            } else {
+                self.eob = true;
+                None
+            }
         } else {
             None
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct MapElements<'a> {
-    key: DecodedElement<'a>,
-    value: DecodedElement<'a>,
+    pub key: DecodedElement<'a>,
+    pub value: DecodedElement<'a>,
 }
 
 impl<'a> MapElements<'a> {
@@ -107,12 +135,18 @@ impl<'a> MapElements<'a> {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// The key-value pairs of a map, decoded one at a time as it is iterated.
+///
+/// Like `ArrayDecoder`, recursing through nested containers with the iterators takes time
+/// that grows with the size of the message times its depth; `walk` takes a single pass.
+#[derive(Copy, Clone, PartialEq)]
 pub struct MapDecoder<'a> {
     header_size: UBytes, // Does not include first byte
     local_endian_fields: bool,
     map: &'a [u8],
-    elements: usize,
+    raw: &'a [u8], // Starts at the header byte of the map
+    elements: usize, // Number of key-value pairs
+    size: Option<usize>, // Total size in bytes, once it is known
     next_idx: usize,
     next_map: usize,
     eob: bool
@@ -121,43 +155,73 @@ pub struct MapDecoder<'a> {
 impl<'a> MapDecoder<'a> {
     /// Get the map starting at the given index
     fn get_at_idx(&self, idx: usize) -> Option<MapElements<'a>> {
-        if let Some(key) = DecodedElement::from_slice_idx(self.map, idx, self.local_endian_fields) {
+        if let Some(mut key) = DecodedElement::from_slice_idx(self.map, idx, self.local_endian_fields) {
             // Key was decoded at the index, so determine its size and look for its value
-            let value_idx = idx + key.byte_size();
+            let value_idx = idx + key.measure();
             if value_idx >= self.map.len() {
                 None
-            } else if let Some(val) = DecodedElement::from_slice_idx(self.map, value_idx, self.local_endian_fields) {
-                Some(MapElements {
-                    key,
-                    value: val
-                })
             } else {
-                None
+                DecodedElement::from_slice_idx(self.map, value_idx, self.local_endian_fields).map(|mut value| {
+                    value.measure();
+                    MapElements {
+                        key,
+                        value
+                    }
+                })
             }
         } else {
             None
         }
 
     }
+    /// Get the number of key-value pairs in the map
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.elements
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get the size of the length field in the header, not including the first byte
+    #[inline]
+    pub fn header_size(&self) -> UBytes {
+        self.header_size
+    }
     /// Reset to the first element
     #[inline]
-    pub fn reset(&mut self) -> () {
+    pub fn reset(&mut self) {
         self.next_map = 0;
         self.next_idx = 0;
         self.eob = false;
     }
     /// Get the total size of the map.
-    /// 
-    /// This operation is very (comparatively) expensive!  It requires consuming all of the map elements in order.
-    pub fn byte_size(&self) -> usize {
-        // Clone a new copy of ourselves such that we can reset it and use it
-        let mut new_self = self.clone();
-        new_self.reset();
-        let mut data_size = 0;
-        for map in new_self {
-            data_size += map.byte_size();
+    ///
+    /// The first call skips over the map with `skip` unless it has already been iterated
+    /// to the end, and stores the result so later calls don't have to.  That is why this
+    /// takes `&mut self`; `DecodedElement::byte_size` measures without storing.
+    pub fn byte_size(&mut self) -> usize {
+        let s = self.known_size();
+        self.size = Some(s);
+        s
+    }
+    /// Get the total size without storing it
+    fn known_size(&self) -> usize {
+        if let Some(s) = self.size {
+            return s;
         }
-        data_size + self.header_size as usize + 1
+        // If the map is truncated, this is the size of what is there
+        skip_partial(self.raw, 0, self.local_endian_fields).0
+    }
+    /// Get the bytes this map was decoded from, including the header
+    #[inline]
+    pub fn raw_bytes(&self) -> &'a [u8] {
+        &self.raw[..self.known_size()]
+    }
+    /// Get the buffer from the start of the map onwards, and how it was decoded
+    #[inline]
+    pub(crate) fn source(&self) -> (&'a [u8], bool) {
+        (self.raw, self.local_endian_fields)
     }
 }
 
@@ -167,7 +231,7 @@ impl<'a> Iterator for MapDecoder<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if  self.next_idx < self.map.len() && 
-            self.next_map < self.elements/2 &&
+            self.next_map < self.elements &&
             !self.eob
         {
             let map_opt = self.get_at_idx(self.next_idx);
@@ -178,8 +242,9 @@ impl<'a> Iterator for MapDecoder<'a> {
                     self.eob = true;
                 };
                 self.next_map += 1;
-                if self.next_map >= self.elements {
-                    // This is also the end of the map so set eob
+                if self.next_map == self.elements {
+                    // Reached the end, so now we know how big the map is
+                    self.size = Some(self.next_idx + self.header_size as usize + 1);
                     self.eob = true;
                 };
                 map_opt
@@ -192,24 +257,81 @@ impl<'a> Iterator for MapDecoder<'a> {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// One step of a path into nested arrays and maps
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PathSegment<'p> {
+    /// The value of the map entry whose key is this string
+    Key(&'p str),
+    /// The array element at this position, or the value of the map entry whose key is this integer
+    Index(usize),
+}
+
+impl<'p> PathSegment<'p> {
+    /// Check whether a map key matches this segment
+    fn matches_key(&self, key: &DecodedElement) -> bool {
+        match (self, key) {
+            (Self::Key(k), DecodedElement::Str{val, ..}) => k == val,
+            (Self::Index(i), DecodedElement::Int{val, ..}) => *val >= 0 && *val as u64 == *i as u64,
+            (Self::Index(i), DecodedElement::UInt{val, ..}) => *val == *i as u64,
+            _ => false
+        }
+    }
+}
+
+/// An element decoded from a buffer.
+///
+/// Every scalar keeps `raw`, the exact bytes it was decoded from, as containers do (see
+/// `raw_bytes`).
+#[derive(Copy, Clone, PartialEq)]
 pub enum DecodedElement<'a> {
-    Nil,
-    Int{size: UBytes, val: i64},
-    UInt{size: UBytes, val: u64},
-    Bool(bool),
-    Bin{header_size: UBytes, val: &'a[u8]},
-    Float(f32),
-    Double(f64),
-    Str{header_size: UBytes, val: &'a str},
+    Nil{raw: &'a [u8]},
+    Int{size: UBytes, val: i64, raw: &'a [u8]},
+    UInt{size: UBytes, val: u64, raw: &'a [u8]},
+    Bool{val: bool, raw: &'a [u8]},
+    Bin{header_size: UBytes, val: &'a[u8], raw: &'a [u8]},
+    Float{val: f32, raw: &'a [u8]},
+    Double{val: f64, raw: &'a [u8]},
+    Str{header_size: UBytes, val: &'a str, raw: &'a [u8]},
     Array(ArrayDecoder<'a>),
     Map(MapDecoder<'a>),
-    Ext{header_size: UBytes, exttype: u8, data: &'a [u8]}
+    Ext{header_size: UBytes, exttype: u8, data: &'a [u8], raw: &'a [u8]}
 }
 
 impl<'a> DecodedElement<'a> {
+    /// Build an array decoder for the array whose header is at `idx`, which the caller has checked
+    fn array(slice: &'a [u8], idx: usize, header_size: UBytes, elements: usize, local_endian_fields: bool) -> Self {
+        Self::Array(ArrayDecoder {
+            header_size,
+            local_endian_fields,
+            elements,
+            array: &slice[idx+1+header_size as usize..],
+            raw: &slice[idx..],
+            next_element: 0,
+            next_idx: 0,
+            size: None,
+            eob: false
+        })
+    }
+    /// Build a map decoder for the map whose header is at `idx`, which the caller has checked
+    fn map(slice: &'a [u8], idx: usize, header_size: UBytes, elements: usize, local_endian_fields: bool) -> Self {
+        Self::Map(MapDecoder {
+            header_size,
+            local_endian_fields,
+            elements,
+            size: None,
+            eob: false,
+            map: &slice[idx+1+header_size as usize..],
+            raw: &slice[idx..],
+            next_idx: 0,
+            next_map: 0,
+        })
+    }
     /// Decode a MessagePack element that begins at `idx` in `slice`.
     pub fn from_slice_idx(slice: &'a [u8], idx: usize, local_endian_fields: bool) -> Option<Self> {
+        Self::decode_at(slice, idx, local_endian_fields).map(Self::trim_raw)
+    }
+    /// Decode the element at `idx`, with every scalar's `raw` running to the end of `slice`
+    fn decode_at(slice: &'a [u8], idx: usize, local_endian_fields: bool) -> Option<Self> {
         /* Like most binary decoders, this is one whole big match expression.
          * We take the header byte, figure out what kind of field it is, and (assuming it is valid) create
          * a DecodedElement from it.
@@ -218,47 +340,33 @@ impl<'a> DecodedElement<'a> {
          * there would have to be some way of lazily evaluating the conversion.  There's not any real
          * benefit to this, though.  So, I have elected to just convert and copy everything that is not
          * big enough to need its own buffer.
+         * Only numeric values follow `local_endian_fields`; lengths are always big-endian (see `endian`).
          */
+        let order = ByteOrder::from_local_endian(local_endian_fields);
+        if idx >= slice.len() {
+            return None;
+        }
+        let raw = &slice[idx..];
         // First, attempt to match the fixints, since they're not easy to do with the match arms 
         if slice[idx] <= 0x7f {
             // This is a positive fixint
-            Some(Self::Int{size: 0, val: slice[idx] as i64})
-        } else if slice[idx] > 0xE0 {
+            Some(Self::Int{size: 0, val: slice[idx] as i64, raw})
+        } else if slice[idx] >= 0xE0 {
             // This is a negative fixint
-            Some(Self::Int{size: 0, val: (slice[idx] as i64) - 256})
+            Some(Self::Int{size: 0, val: (slice[idx] as i64) - 256, raw})
         } else if slice[idx] >= 0x80 && slice[idx] <= 0x8F {
             // Fixmap
-            let elements: usize = (slice[idx] & 0x0F) as usize;
-            let decoder = MapDecoder {
-                header_size: 0,
-                local_endian_fields,
-                elements,
-                eob: false,
-                map: &slice[idx+1..],
-                next_idx: 0,
-                next_map: 0,
-            };
-            Some(Self::Map(decoder))
+            Some(Self::map(slice, idx, 0, (slice[idx] & 0x0F) as usize, local_endian_fields))
         } else if slice[idx] >= 0x90 && slice[idx] <= 0x9F {
             // Fixarray
-            let elements: usize = (slice[idx] & 0x0F) as usize;
-            let decoder = ArrayDecoder {
-                header_size: 0,
-                local_endian_fields,
-                elements,
-                array: &slice[idx+1..],
-                next_element: 0,
-                element_size: None,
-                eob: false
-            };
-            Some(Self::Array(decoder))
+            Some(Self::array(slice, idx, 0, (slice[idx] & 0x0F) as usize, local_endian_fields))
         } else if slice[idx] >= 0xA0 && slice[idx] <= 0xBF {
             // Fixstr
             let length: usize = (slice[idx] & 0x1F) as usize;
             // Check that we have enough length
             if idx + length < slice.len() {
                 if let Ok(s) = str::from_utf8(&slice[idx+1..idx+1+length]) {
-                    Some(Self::Str{header_size: 0, val: s})
+                    Some(Self::Str{header_size: 0, val: s, raw})
                 } else {
                     None
                 }
@@ -268,165 +376,69 @@ impl<'a> DecodedElement<'a> {
         } else {
             match slice[idx] {
                 // Nil
-                0xC0 => Some(Self::Nil),
+                0xC0 => Some(Self::Nil{raw}),
+                // Arrays and maps with their length in a separate field
+                0xDC => ByteOrder::Big.read::<u16>(slice, idx+1)
+                    .map(|len| Self::array(slice, idx, 2, len as usize, local_endian_fields)),
+                0xDD => ByteOrder::Big.read::<u32>(slice, idx+1)
+                    .map(|len| Self::array(slice, idx, 4, len as usize, local_endian_fields)),
+                0xDE => ByteOrder::Big.read::<u16>(slice, idx+1)
+                    .map(|len| Self::map(slice, idx, 2, len as usize, local_endian_fields)),
+                0xDF => ByteOrder::Big.read::<u32>(slice, idx+1)
+                    .map(|len| Self::map(slice, idx, 4, len as usize, local_endian_fields)),
                 // Unsigned ints
                 0xCC => {
                     // 8-bit uint
                     if idx+1 < slice.len() {
-                        Some(Self::UInt{size: 1, val: slice[idx+1] as u64})
+                        Some(Self::UInt{size: 1, val: slice[idx+1] as u64, raw})
                     } else {
                         None
                     }
                 },
                 0xCD => {
                     // 16-bit uint
-                    if idx+2 < slice.len() {
-                        // Attempt to derive a u16 from this
-                        if let Ok(uint_bytes) = slice[idx+1..idx+3].try_into() {
-                            if local_endian_fields {
-                                Some(Self::UInt{size: 2, val: u16::from_le_bytes(uint_bytes) as u64})
-                            } else {
-                                Some(Self::UInt{size: 2, val: u16::from_be_bytes(uint_bytes) as u64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<u16>(slice, idx+1).map(|val| Self::UInt{size: 2, val: val as u64, raw})
                 },
                 0xCE => {
                     // 32-bit uint
-                    if idx+4 < slice.len() {
-                        // Attempt to derive a u32 from this
-                        if let Ok(uint_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                Some(Self::UInt{size: 4, val: u32::from_le_bytes(uint_bytes) as u64})
-                            } else {
-                                Some(Self::UInt{size: 4, val: u32::from_be_bytes(uint_bytes) as u64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<u32>(slice, idx+1).map(|val| Self::UInt{size: 4, val: val as u64, raw})
                 },
                 0xCF => {
                     // 64-bit uint
-                    if idx+8 < slice.len() {
-                        // Attempt to derive a u64 from this
-                        if let Ok(uint_bytes) = slice[idx+1..idx+9].try_into() {
-                            if local_endian_fields {
-                                Some(Self::UInt{size: 8, val: u64::from_le_bytes(uint_bytes) as u64})
-                            } else {
-                                Some(Self::UInt{size: 8, val: u64::from_be_bytes(uint_bytes) as u64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<u64>(slice, idx+1).map(|val| Self::UInt{size: 8, val, raw})
                 },
                 // Signed Ints
                 0xD0 => {
                     // 8-bit int
                     if idx+1 < slice.len() {
-                        let bytes: [u8; 2] = [0x0, slice[idx+1]];
-                        Some(Self::Int{size: 1, val: i16::from_be_bytes(bytes) as i64})
+                        Some(Self::Int{size: 1, val: slice[idx+1] as i8 as i64, raw})
                     } else {
                         None
                     }
                 },
                 0xD1 => {
                     // 16-bit int
-                    if idx+2 < slice.len() {
-                        // Attempt to derive a u16 from this
-                        if let Ok(int_bytes) = slice[idx+1..idx+3].try_into() {
-                            if local_endian_fields {
-                                Some(Self::Int{size: 2, val: i16::from_le_bytes(int_bytes) as i64})
-                            } else {
-                                Some(Self::Int{size: 2, val: i16::from_be_bytes(int_bytes) as i64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<i16>(slice, idx+1).map(|val| Self::Int{size: 2, val: val as i64, raw})
                 },
                 0xD2 => {
                     // 32-bit int
-                    if idx+4 < slice.len() {
-                        // Attempt to derive a i32 from this
-                        if let Ok(int_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                Some(Self::Int{size: 4, val: i32::from_le_bytes(int_bytes) as i64})
-                            } else {
-                                Some(Self::Int{size: 4, val: i32::from_be_bytes(int_bytes) as i64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<i32>(slice, idx+1).map(|val| Self::Int{size: 4, val: val as i64, raw})
                 },
                 0xD3 => {
                     // 64-bit int
-                    if idx+8 < slice.len() {
-                        // Attempt to derive a i64 from this
-                        if let Ok(int_bytes) = slice[idx+1..idx+9].try_into() {
-                            if local_endian_fields {
-                                Some(Self::Int{size: 8, val: i64::from_le_bytes(int_bytes) as i64})
-                            } else {
-                                Some(Self::Int{size: 8, val: i64::from_be_bytes(int_bytes) as i64})
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<i64>(slice, idx+1).map(|val| Self::Int{size: 8, val, raw})
                 },
                 // Booleans
-                0xC2 => Some(Self::Bool(false)),
-                0xC3 => Some(Self::Bool(true)),
+                0xC2 => Some(Self::Bool{val: false, raw}),
+                0xC3 => Some(Self::Bool{val: true, raw}),
                 // Floats
                 0xCA => {
                     // f32
-                    if idx+4 < slice.len() {
-                        // Attempt to derive an f32 from this
-                        if let Ok(float_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                Some(Self::Float(f32::from_le_bytes(float_bytes)))
-                            } else {
-                                Some(Self::Float(f32::from_be_bytes(float_bytes)))
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<f32>(slice, idx+1).map(|val| Self::Float{val, raw})
                 },
                 0xCB => {
                     // f64
-                    if idx+8 < slice.len() {
-                        // Attempt to derive an f64 from this
-                        if let Ok(float_bytes) = slice[idx+1..idx+9].try_into() {
-                            if local_endian_fields {
-                                Some(Self::Double(f64::from_le_bytes(float_bytes)))
-                            } else {
-                                Some(Self::Double(f64::from_be_bytes(float_bytes)))
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
+                    order.read::<f64>(slice, idx+1).map(|val| Self::Double{val, raw})
                 },
                 0xD9 => {
                     // str 8
@@ -435,7 +447,7 @@ impl<'a> DecodedElement<'a> {
                         // Build a slice from the given information
                         if idx + 1 + length < slice.len() {
                             if let Ok(s) = str::from_utf8(&slice[idx+2..idx+2+length]) {
-                                Some(Self::Str{header_size: 1, val: s})
+                                Some(Self::Str{header_size: 1, val: s, raw})
                             } else {
                                 None
                             }
@@ -448,18 +460,10 @@ impl<'a> DecodedElement<'a> {
                 },
                 0xDA => {
                     // str 16
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+3].try_into() {
-                            if local_endian_fields {
-                                u16::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u16::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u16>(slice, idx+1)? as usize;
                     if idx+2+size < slice.len() {
                         if let Ok(s) = str::from_utf8(&slice[idx+3..idx+3+size]) {
-                            Some(Self::Str{header_size: 2, val: s})
+                            Some(Self::Str{header_size: 2, val: s, raw})
                         } else {
                             None
                         }
@@ -469,18 +473,10 @@ impl<'a> DecodedElement<'a> {
                 },
                 0xDB => {
                     // str 32
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                u32::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u32::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u32>(slice, idx+1)? as usize;
                     if idx+4+size < slice.len() {
                         if let Ok(s) = str::from_utf8(&slice[idx+5..idx+5+size]) {
-                            Some(Self::Str{header_size: 4, val: s})
+                            Some(Self::Str{header_size: 4, val: s, raw})
                         } else {
                             None
                         }
@@ -494,7 +490,7 @@ impl<'a> DecodedElement<'a> {
                         let length: usize  = slice[idx+1] as usize;
                         // Build a slice from the given information
                         if idx + 1 + length < slice.len() {
-                            Some(Self::Bin{header_size: 1, val: &slice[idx+2..idx+2+length]})
+                            Some(Self::Bin{header_size: 1, val: &slice[idx+2..idx+2+length], raw})
                         } else {
                             None
                         }
@@ -504,34 +500,18 @@ impl<'a> DecodedElement<'a> {
                 },
                 0xC5 => {
                     // bin 16
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+3].try_into() {
-                            if local_endian_fields {
-                                u16::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u16::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u16>(slice, idx+1)? as usize;
                     if idx+2+size < slice.len() {
-                        Some(Self::Bin{header_size: 2, val: &slice[idx+3..idx+3+size]})
+                        Some(Self::Bin{header_size: 2, val: &slice[idx+3..idx+3+size], raw})
                     } else {
                         None
                     }
                 },
                 0xC6 => {
                     // bin 32
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                u32::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u32::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u32>(slice, idx+1)? as usize;
                     if idx+4+size < slice.len() {
-                        Some(Self::Bin{header_size: 4, val: &slice[idx+5..idx+5+size]})
+                        Some(Self::Bin{header_size: 4, val: &slice[idx+5..idx+5+size], raw})
                     } else {
                         None
                     }
@@ -543,8 +523,8 @@ impl<'a> DecodedElement<'a> {
                         let length: usize  = slice[idx+1] as usize;
                         let t: u8 = slice[idx+2];
                         // Build a slice from the given information
-                        if idx + 1 + length < slice.len() {
-                            Some(Self::Ext{header_size: 1, exttype: t, data: &slice[idx+3..idx+3+length]})
+                        if idx + 2 + length < slice.len() {
+                            Some(Self::Ext{header_size: 1, exttype: t, data: &slice[idx+3..idx+3+length], raw})
                         } else {
                             None
                         }
@@ -554,36 +534,20 @@ impl<'a> DecodedElement<'a> {
                 },
                 0xC8 => {
                     // ext 16
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+3].try_into() {
-                            if local_endian_fields {
-                                u16::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u16::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u16>(slice, idx+1)? as usize;
                     if idx+3+size < slice.len() {
                         let t: u8 = slice[idx+3];
-                        Some(Self::Ext{header_size: 2, exttype: t, data: &slice[idx+4..idx+4+size]})
+                        Some(Self::Ext{header_size: 2, exttype: t, data: &slice[idx+4..idx+4+size], raw})
                     } else {
                         None
                     }
                 },
                 0xC9 => {
                     // ext 32
-                    let size: usize = if let Ok(size_bytes) = slice[idx+1..idx+5].try_into() {
-                            if local_endian_fields {
-                                u32::from_le_bytes(size_bytes) as usize
-                            } else {
-                                u32::from_be_bytes(size_bytes) as usize
-                            }
-                    } else {
-                        return None;
-                    };
+                    let size = ByteOrder::Big.read::<u32>(slice, idx+1)? as usize;
                     if idx+5+size < slice.len() {
-                        let t: u8 = slice[idx+4];
-                        Some(Self::Ext{header_size: 4, exttype: t, data: &slice[idx+5..idx+5+size]})
+                        let t: u8 = slice[idx+5];
+                        Some(Self::Ext{header_size: 4, exttype: t, data: &slice[idx+6..idx+6+size], raw})
                     } else {
                         None
                     }
@@ -592,39 +556,39 @@ impl<'a> DecodedElement<'a> {
                 0xD4 => {
                     // fixext 1
                     if idx+2 < slice.len() {
-                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+3]})
+                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+3], raw})
                     } else {
                         None
                     }
                 },
                 0xD5 => {
                     // fixext 2
-                    if idx+4 < slice.len() {
-                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+4]})
+                    if idx+3 < slice.len() {
+                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+4], raw})
                     } else {
                         None
                     }
                 },
                 0xD6 => {
                     // fixext 4
-                    if idx+6 < slice.len() {
-                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+6]})
+                    if idx+5 < slice.len() {
+                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+6], raw})
                     } else {
                         None
                     }
                 },
                 0xD7 => {
                     // fixext 8
-                    if idx+10 < slice.len() {
-                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+10]})
+                    if idx+9 < slice.len() {
+                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+10], raw})
                     } else {
                         None
                     }
                 },
                 0xD8 => {
-                    // fixext 8
-                    if idx+18 < slice.len() {
-                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+18]})
+                    // fixext 16
+                    if idx+17 < slice.len() {
+                        Some(Self::Ext{header_size: 0, exttype: slice[idx+1], data: &slice[idx+2..idx+18], raw})
                     } else {
                         None
                     }
@@ -633,24 +597,675 @@ impl<'a> DecodedElement<'a> {
             }
         }
     }
+    /// Find the element at `path` below the element that begins at `idx` in `slice`.
+    ///
+    /// Returns the element along with the index in `slice` that it begins at.
+    pub fn locate(slice: &'a [u8], idx: usize, path: &[PathSegment], local_endian_fields: bool) -> Option<(Self, usize)> {
+        let mut el = Self::from_slice_idx(slice, idx, local_endian_fields)?;
+        let mut el_idx = idx;
+        for seg in path {
+            // Walk the children of the current element, keeping track of where each one starts
+            (el, el_idx) = match (el, seg) {
+                (Self::Array(mut a), PathSegment::Index(n)) => {
+                    a.reset();
+                    let mut child_idx = el_idx + 1 + a.header_size() as usize;
+                    let mut found = None;
+                    for (i, child) in a.enumerate() {
+                        if i == *n {
+                            found = Some((child, child_idx));
+                            break;
+                        }
+                        child_idx += child.byte_size();
+                    }
+                    found?
+                },
+                (Self::Map(mut m), _) => {
+                    m.reset();
+                    let mut key_idx = el_idx + 1 + m.header_size() as usize;
+                    let mut found = None;
+                    for kv in m {
+                        let value_idx = key_idx + kv.key.byte_size();
+                        if seg.matches_key(&kv.key) {
+                            found = Some((kv.value, value_idx));
+                            break;
+                        }
+                        key_idx = value_idx + kv.value.byte_size();
+                    }
+                    found?
+                },
+                _ => return None
+            };
+        }
+        Some((el, el_idx))
+    }
+    /// Get the name of the MessagePack type of this element
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil{..} => "nil",
+            Self::Int{..} | Self::UInt{..} => "int",
+            Self::Bool{..} => "bool",
+            Self::Bin{..} => "bin",
+            Self::Float{..} | Self::Double{..} => "float",
+            Self::Str{..} => "str",
+            Self::Array(_) => "array",
+            Self::Map(_) => "map",
+            Self::Ext{..} => "ext",
+        }
+    }
+    /// Get the value of a float as an f64, whichever width it was sent with.
+    ///
+    /// Ints are converted too, since peers may send integral floats as ints (see
+    /// `EncodeOptions::integral_floats_as_ints`).  Ints beyond 2^53 are rounded.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Float{val, ..} => Some(*val as f64),
+            Self::Double{val, ..} => Some(*val),
+            Self::Int{val, ..} => Some(*val as f64),
+            Self::UInt{val, ..} => Some(*val as f64),
+            _ => None
+        }
+    }
+    /// Get the exact bytes this element was decoded from, header included.
+    ///
+    /// This is a slice of the buffer the element was decoded from, not a copy.  For a
+    /// truncated container it is as much of the container as there is.
+    pub fn raw_bytes(&self) -> &'a [u8] {
+        match self {
+            Self::Array(a) => a.raw_bytes(),
+            Self::Map(m) => m.raw_bytes(),
+            Self::Nil{raw} | Self::Int{raw, ..} | Self::UInt{raw, ..} | Self::Bool{raw, ..} | Self::Bin{raw, ..} |
+            Self::Float{raw, ..} | Self::Double{raw, ..} | Self::Str{raw, ..} | Self::Ext{raw, ..} => raw
+        }
+    }
+    /// Cut a scalar's `raw` down to the bytes of the element
+    fn trim_raw(mut self) -> Self {
+        // Containers are left alone: their size is only known once they have been walked
+        if matches!(self, Self::Array(_) | Self::Map(_)) {
+            return self;
+        }
+        let size = self.byte_size();
+        match &mut self {
+            Self::Array(_) | Self::Map(_) => {},
+            Self::Nil{raw} | Self::Int{raw, ..} | Self::UInt{raw, ..} | Self::Bool{raw, ..} | Self::Bin{raw, ..} |
+            Self::Float{raw, ..} | Self::Double{raw, ..} | Self::Str{raw, ..} | Self::Ext{raw, ..} => *raw = &raw[..size],
+        }
+        self
+    }
+    /// Check that every element inside a container could be decoded.
+    ///
+    /// A truncated or corrupt container stops iterating early, so this goes through
+    /// `skip`, which fails if any element the header promised is missing.
+    pub fn is_complete(&self) -> bool {
+        match self {
+            Self::Array(a) => skip(a.raw, 0, a.local_endian_fields).is_ok(),
+            Self::Map(m) => skip(m.raw, 0, m.local_endian_fields).is_ok(),
+            _ => true
+        }
+    }
+    /// Get the size of this element, remembering it if it is a container so that later
+    /// calls to `byte_size` don't have to walk it again
+    fn measure(&mut self) -> usize {
+        match self {
+            Self::Array(a) => a.byte_size(),
+            Self::Map(m) => m.byte_size(),
+            _ => self.byte_size()
+        }
+    }
     /// Get the size, in bytes, of the MesagePack representation this element was decoded from
     pub fn byte_size(&self) -> usize {
         /* We cannot assume that the item was expressed in the most compact form,
          * so we saved the size of the decoded element when we decoded it. */
         match self {
-            Self::Nil => 1,
-            Self::Int{size: s, val: _} => *s as usize + 1, // Always one overhead byte for Int and Uint, because 0 for size is an option (fixint)
-            Self::UInt{size: s, val: _} => *s as usize + 1,
-            Self::Bool(_) => 1,
-            Self::Bin{header_size: hs, val: v} => *hs as usize + v.len() as usize + 1,
-            Self::Float(_) => 5,
-            Self::Double(_) => 9,
-            Self::Str{header_size: hs, val: v} => *hs as usize + v.len() as usize + 1,
-            Self::Ext{header_size: hs, data: d, ..} => *hs as usize + d.len() as usize + 2,
-            Self::Array(a) => a.byte_size(),
-            Self::Map(m) => m.byte_size(),
+            Self::Nil{..} => 1,
+            Self::Int{size: s, ..} => *s as usize + 1, // Always one overhead byte for Int and Uint, because 0 for size is an option (fixint)
+            Self::UInt{size: s, ..} => *s as usize + 1,
+            Self::Bool{..} => 1,
+            Self::Bin{header_size: hs, val: v, ..} => *hs as usize + v.len() + 1,
+            Self::Float{..} => 5,
+            Self::Double{..} => 9,
+            Self::Str{header_size: hs, val: v, ..} => *hs as usize + v.len() + 1,
+            Self::Ext{header_size: hs, data: d, ..} => *hs as usize + d.len() + 2,
+            Self::Array(a) => a.known_size(),
+            Self::Map(m) => m.known_size(),
+        }
+    }
+}
+
+/// Get the header and payload length of the string starting at `idx` without checking that it is valid UTF-8
+fn str_len(buf: &[u8], idx: usize) -> Option<(usize, usize)> {
+    let marker = *buf.get(idx)?;
+    match marker {
+        0xA0..=0xBF => Some((0, (marker & 0x1F) as usize)),
+        0xD9 => Some((1, *buf.get(idx+1)? as usize)),
+        0xDA => Some((2, ByteOrder::Big.read::<u16>(buf, idx+1)? as usize)),
+        0xDB => Some((4, ByteOrder::Big.read::<u32>(buf, idx+1)? as usize)),
+        _ => None
+    }
+}
+
+/// Find the end of the element that begins at `idx` in `buf`, without decoding it.
+///
+/// Rather than recursing into containers, this keeps a count of the elements it still
+/// has to skip, so it uses constant memory however deeply the input is nested.  Strings
+/// are skipped without checking that they are valid UTF-8.
+///
+/// Returns the index of the first byte after the element, or `Error::InvalidData` if
+/// the element or anything inside it is truncated or invalid.
+pub fn skip(buf: &[u8], idx: usize, local_endian_fields: bool) -> Result<usize> {
+    match skip_partial(buf, idx, local_endian_fields) {
+        (end, true) => Ok(end),
+        (_, false) => Err(Error::InvalidData)
+    }
+}
+
+/// Skip the element at `idx` as far as possible, returning where it stopped and whether
+/// it got to the end of the element
+fn skip_partial(buf: &[u8], idx: usize, local_endian_fields: bool) -> (usize, bool) {
+    let mut idx = idx;
+    let mut pending: usize = 1;
+    while pending > 0 {
+        pending -= 1;
+        if let Some((header_size, len)) = str_len(buf, idx) {
+            if idx + 1 + header_size + len > buf.len() {
+                return (buf.len(), false);
+            }
+            idx += 1 + header_size + len;
+            continue;
+        }
+        match DecodedElement::from_slice_idx(buf, idx, local_endian_fields) {
+            Some(DecodedElement::Array(a)) => {
+                pending = match pending.checked_add(a.len()) {
+                    Some(p) => p,
+                    None => return (idx, false)
+                };
+                idx += 1 + a.header_size() as usize;
+            },
+            Some(DecodedElement::Map(m)) => {
+                pending = match m.len().checked_mul(2).and_then(|n| pending.checked_add(n)) {
+                    Some(p) => p,
+                    None => return (idx, false)
+                };
+                idx += 1 + m.header_size() as usize;
+            },
+            Some(el) => idx += el.byte_size(),
+            None => return (idx, false)
         }
     }
+    (idx, true)
+}
+
+/// How much of the first element of a buffer has arrived, from `probe`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Probe {
+    /// The element is complete and takes this many bytes
+    Complete(usize),
+    /// The buffer ends inside the element, which needs at least this many bytes in total
+    Incomplete(usize),
+}
+
+/// Find out whether `buf` begins with a whole element, for readers that receive messages
+/// a piece at a time.
+///
+/// Like `skip`, this uses constant memory and doesn't check strings.  `Incomplete` gives
+/// the least the element can take given the bytes so far, so a reader can wait for that
+/// much and probe again.  Bytes that can't begin an element fail with `Error::InvalidData`.
+pub fn probe(buf: &[u8], local_endian_fields: bool) -> Result<Probe> {
+    let mut idx = 0;
+    let mut pending: usize = 1;
+    while pending > 0 {
+        pending -= 1;
+        if let Some((header_size, len)) = str_len(buf, idx) {
+            idx += 1 + header_size + len;
+            if idx > buf.len() {
+                return Ok(Probe::Incomplete(idx));
+            }
+            continue;
+        }
+        match DecodedElement::from_slice_idx(buf, idx, local_endian_fields) {
+            Some(DecodedElement::Array(a)) => {
+                pending = pending.checked_add(a.len()).ok_or(Error::InvalidData)?;
+                idx += 1 + a.header_size() as usize;
+            },
+            Some(DecodedElement::Map(m)) => {
+                pending = m.len().checked_mul(2).and_then(|n| pending.checked_add(n)).ok_or(Error::InvalidData)?;
+                idx += 1 + m.header_size() as usize;
+            },
+            Some(el) => idx += el.byte_size(),
+            None => {
+                let need = idx + min_size(buf, idx);
+                // Anything that fits in the buffer and still didn't decode is invalid
+                return if need > buf.len() { Ok(Probe::Incomplete(need)) } else { Err(Error::InvalidData) };
+            }
+        }
+    }
+    Ok(Probe::Complete(idx))
+}
+
+/// The least the element at `idx` can take, given the part of it that is in `buf`
+fn min_size(buf: &[u8], idx: usize) -> usize {
+    let Some(&marker) = buf.get(idx) else { return 1 };
+    // The bytes between the marker and the data, and how many of them give the data length
+    let (header_size, len_size) = match marker {
+        0xC4 | 0xD9 => (1, 1),
+        0xC5 | 0xDA => (2, 2),
+        0xC6 | 0xDB => (4, 4),
+        0xC7 => (2, 1),
+        0xC8 => (3, 2),
+        0xC9 => (5, 4),
+        0xCC | 0xD0 => (1, 0),
+        0xCD | 0xD1 | 0xD4 | 0xDC | 0xDE => (2, 0),
+        0xD5 => (3, 0),
+        0xCA | 0xCE | 0xD2 | 0xDD | 0xDF => (4, 0),
+        0xD6 => (5, 0),
+        0xCB | 0xCF | 0xD3 => (8, 0),
+        0xD7 => (9, 0),
+        0xD8 => (17, 0),
+        _ => (0, 0)
+    };
+    let len = match len_size {
+        1 => buf.get(idx+1).map(|&l| l as usize),
+        2 => ByteOrder::Big.read::<u16>(buf, idx+1).map(usize::from),
+        4 => ByteOrder::Big.read::<u32>(buf, idx+1).map(|l| l as usize),
+        _ => None
+    };
+    1 + header_size + len.unwrap_or(0)
+}
+
+/// Get the name of the MessagePack format that begins with `marker`
+pub fn marker_name(marker: u8) -> &'static str {
+    match marker {
+        0x00..=0x7F => "positive fixint",
+        0x80..=0x8F => "fixmap",
+        0x90..=0x9F => "fixarray",
+        0xA0..=0xBF => "fixstr",
+        0xC0 => "nil",
+        0xC1 => "never used",
+        0xC2 => "false",
+        0xC3 => "true",
+        0xC4 => "bin 8",
+        0xC5 => "bin 16",
+        0xC6 => "bin 32",
+        0xC7 => "ext 8",
+        0xC8 => "ext 16",
+        0xC9 => "ext 32",
+        0xCA => "float 32",
+        0xCB => "float 64",
+        0xCC => "uint 8",
+        0xCD => "uint 16",
+        0xCE => "uint 32",
+        0xCF => "uint 64",
+        0xD0 => "int 8",
+        0xD1 => "int 16",
+        0xD2 => "int 32",
+        0xD3 => "int 64",
+        0xD4 => "fixext 1",
+        0xD5 => "fixext 2",
+        0xD6 => "fixext 4",
+        0xD7 => "fixext 8",
+        0xD8 => "fixext 16",
+        0xD9 => "str 8",
+        0xDA => "str 16",
+        0xDB => "str 32",
+        0xDC => "array 16",
+        0xDD => "array 32",
+        0xDE => "map 16",
+        0xDF => "map 32",
+        0xE0..=0xFF => "negative fixint",
+    }
+}
+
+/// Callbacks for `walk`, called in the order the elements appear in the buffer.
+///
+/// Every method does nothing by default, so a visitor only needs to implement the ones it cares about.
+#[allow(unused_variables)]
+pub trait Visitor<'a> {
+    /// Called with the index and decoded form of every element, before the more specific callback
+    fn visit_element(&mut self, idx: usize, el: &DecodedElement<'a>) {}
+    fn visit_nil(&mut self) {}
+    fn visit_int(&mut self, val: i64) {}
+    fn visit_uint(&mut self, val: u64) {}
+    fn visit_bool(&mut self, val: bool) {}
+    fn visit_float(&mut self, val: f32) {}
+    fn visit_double(&mut self, val: f64) {}
+    fn visit_str(&mut self, val: &'a str) {}
+    fn visit_bin(&mut self, val: &'a [u8]) {}
+    fn visit_ext(&mut self, exttype: u8, data: &'a [u8]) {}
+    /// Called before the elements of an array with `len` elements
+    fn enter_array(&mut self, len: usize) {}
+    fn exit_array(&mut self) {}
+    /// Called before the entries of a map with `len` key-value pairs
+    fn enter_map(&mut self, len: usize) {}
+    /// Called before each key of a map
+    fn map_key(&mut self) {}
+    /// Called before each value of a map
+    fn map_value(&mut self) {}
+    fn exit_map(&mut self) {}
+}
+
+/// Walk the element at the start of `buf`, calling `visitor` for everything inside it.
+///
+/// Unlike iterating with `ArrayDecoder` and `MapDecoder`, this makes a single pass over
+/// the buffer and never needs the size of a container up front.  Nesting is tracked with
+/// a fixed stack rather than recursion, so deeply nested input returns `Error::TooDeep`.
+///
+/// Returns the number of bytes the element took up.
+pub fn walk<'a, V: Visitor<'a>>(buf: &'a [u8], visitor: &mut V, local_endian_fields: bool) -> Result<usize> {
+    // Elements left in each open container, and whether it is a map.  Maps count keys
+    // and values separately, so an even count means a key comes next.
+    let mut remaining = [0_usize; MAX_DEPTH];
+    let mut is_map = [false; MAX_DEPTH];
+    let mut depth = 0;
+    let mut idx = 0;
+    loop {
+        if depth > 0 {
+            if is_map[depth-1] {
+                if remaining[depth-1] % 2 == 0 {
+                    visitor.map_key();
+                } else {
+                    visitor.map_value();
+                }
+            }
+            remaining[depth-1] -= 1;
+        }
+        let el = DecodedElement::from_slice_idx(buf, idx, local_endian_fields).ok_or(Error::InvalidData)?;
+        visitor.visit_element(idx, &el);
+        match el {
+            DecodedElement::Array(a) => {
+                if depth == MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                visitor.enter_array(a.len());
+                remaining[depth] = a.len();
+                is_map[depth] = false;
+                depth += 1;
+                idx += 1 + a.header_size() as usize;
+            },
+            DecodedElement::Map(m) => {
+                if depth == MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                remaining[depth] = m.len().checked_mul(2).ok_or(Error::InvalidData)?;
+                visitor.enter_map(m.len());
+                is_map[depth] = true;
+                depth += 1;
+                idx += 1 + m.header_size() as usize;
+            },
+            DecodedElement::Nil{..} => visitor.visit_nil(),
+            DecodedElement::Int{val, ..} => visitor.visit_int(val),
+            DecodedElement::UInt{val, ..} => visitor.visit_uint(val),
+            DecodedElement::Bool{val: b, ..} => visitor.visit_bool(b),
+            DecodedElement::Float{val: f, ..} => visitor.visit_float(f),
+            DecodedElement::Double{val: d, ..} => visitor.visit_double(d),
+            DecodedElement::Str{val, ..} => visitor.visit_str(val),
+            DecodedElement::Bin{val, ..} => visitor.visit_bin(val),
+            DecodedElement::Ext{exttype, data, ..} => visitor.visit_ext(exttype, data),
+        }
+        // Containers only skip their header, so their elements are visited next
+        if !matches!(el, DecodedElement::Array(_) | DecodedElement::Map(_)) {
+            idx += el.byte_size();
+        }
+        // Close every container that has run out of elements
+        while depth > 0 && remaining[depth-1] == 0 {
+            depth -= 1;
+            if is_map[depth] {
+                visitor.exit_map();
+            } else {
+                visitor.exit_array();
+            }
+        }
+        if depth == 0 {
+            return Ok(idx);
+        }
+    }
+}
+
+/// A Rust value that can be read from a decoded element.
+///
+/// Arrays decode into `Vec`s, fixed-size arrays and tuples; the last two must have exactly
+/// as many elements as the array.  Bin decodes into `&[u8]`, while `Vec<u8>` expects an
+/// array of ints.  Nil decodes into `None`.
+///
+/// Anything that doesn't fit the type, such as a str where an int was expected or an int
+/// that is out of range, fails with `Error::TypeMismatch`.
+pub trait Decode<'a>: Sized {
+    fn decode(el: DecodedElement<'a>) -> Result<Self>;
+}
+
+impl<'a> Decode<'a> for DecodedElement<'a> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        Ok(el)
+    }
+}
+
+impl<'a> Decode<'a> for ArrayDecoder<'a> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Array(a) => Ok(a),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+impl<'a> Decode<'a> for MapDecoder<'a> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Map(m) => Ok(m),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+/// Decode the element at the start of `buf` as a `T`
+pub fn from_slice<'a, T: Decode<'a>>(buf: &'a [u8], local_endian_fields: bool) -> Result<T> {
+    T::decode(DecodedElement::from_slice_idx(buf, 0, local_endian_fields).ok_or(Error::InvalidData)?)
+}
+
+/// Implement `Decode` for integer types, from either kind of int element
+macro_rules! impl_decode_int {
+    ($($t:ty),*) => {$(
+        impl<'a> Decode<'a> for $t {
+            fn decode(el: DecodedElement<'a>) -> Result<Self> {
+                match el {
+                    DecodedElement::Int{val, ..} => <$t>::try_from(val).map_err(|_| Error::TypeMismatch),
+                    DecodedElement::UInt{val, ..} => <$t>::try_from(val).map_err(|_| Error::TypeMismatch),
+                    _ => Err(Error::TypeMismatch)
+                }
+            }
+        }
+    )*};
+}
+
+impl_decode_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Accepts any number, since peers may send integral floats as ints
+impl<'a> Decode<'a> for f64 {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        el.as_f64().ok_or(Error::TypeMismatch)
+    }
+}
+
+/// Accepts any number that f32 holds exactly
+impl<'a> Decode<'a> for f32 {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Float{val: f, ..} => Ok(f),
+            _ => match el.as_f64() {
+                Some(d) if d.is_nan() || (d as f32) as f64 == d => Ok(d as f32),
+                _ => Err(Error::TypeMismatch)
+            }
+        }
+    }
+}
+
+impl<'a> Decode<'a> for bool {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Bool{val: b, ..} => Ok(b),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+impl<'a> Decode<'a> for &'a str {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Str{val, ..} => Ok(val),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+impl<'a> Decode<'a> for &'a [u8] {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Bin{val, ..} => Ok(val),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+impl<'a, T: Decode<'a>> Decode<'a> for Option<T> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        match el {
+            DecodedElement::Nil{..} => Ok(None),
+            _ => T::decode(el).map(Some)
+        }
+    }
+}
+
+/// Get the elements of an array that should have `len` of them
+fn array_of_len(el: DecodedElement, len: usize) -> Result<ArrayDecoder> {
+    match el {
+        DecodedElement::Array(mut a) if a.len() == len => {
+            a.reset();
+            Ok(a)
+        },
+        _ => Err(Error::TypeMismatch)
+    }
+}
+
+/// Decode the next element of an array, which has already been checked to be long enough
+fn next_element<'a, T: Decode<'a>>(items: &mut ArrayDecoder<'a>) -> Result<T> {
+    // A truncated array runs out before its length says it should
+    T::decode(items.next().ok_or(Error::InvalidData)?)
+}
+
+impl<'a, T: Decode<'a>, const N: usize> Decode<'a> for [T; N] {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let mut items = array_of_len(el, N)?;
+        let mut out: [Option<T>; N] = core::array::from_fn(|_| None);
+        for slot in out.iter_mut() {
+            *slot = Some(next_element(&mut items)?);
+        }
+        Ok(out.map(|item| item.expect("every slot was filled")))
+    }
+}
+
+/// Implement `Decode` for a tuple, from an array with one element per field
+macro_rules! impl_decode_tuple {
+    ($len:expr; $($name:ident),+) => {
+        impl<'a, $($name: Decode<'a>),+> Decode<'a> for ($($name,)+) {
+            fn decode(el: DecodedElement<'a>) -> Result<Self> {
+                let mut _items = array_of_len(el, $len)?;
+                Ok(($(next_element::<$name>(&mut _items)?,)+))
+            }
+        }
+    };
+}
+
+impl_decode_tuple!(1; A);
+impl_decode_tuple!(2; A, B);
+impl_decode_tuple!(3; A, B, C);
+impl_decode_tuple!(4; A, B, C, D);
+impl_decode_tuple!(5; A, B, C, D, E);
+impl_decode_tuple!(6; A, B, C, D, E, F);
+impl_decode_tuple!(7; A, B, C, D, E, F, G);
+impl_decode_tuple!(8; A, B, C, D, E, F, G, H);
+
+#[cfg(feature = "alloc")]
+impl<'a> Decode<'a> for alloc::string::String {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        <&str>::decode(el).map(Into::into)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, T: Decode<'a>> Decode<'a> for alloc::vec::Vec<T> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let DecodedElement::Array(a) = el else { return Err(Error::TypeMismatch) };
+        let mut items = array_of_len(el, a.len())?;
+        (0..a.len()).map(|_| next_element(&mut items)).collect()
+    }
+}
+
+/// If a key appears more than once, the last value wins
+#[cfg(feature = "alloc")]
+impl<'a, K: Decode<'a> + Ord, V: Decode<'a>> Decode<'a> for alloc::collections::BTreeMap<K, V> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let DecodedElement::Map(mut m) = el else { return Err(Error::TypeMismatch) };
+        m.reset();
+        let len = m.len();
+        let mut out = alloc::collections::BTreeMap::new();
+        let mut count = 0;
+        for kv in m {
+            out.insert(K::decode(kv.key)?, V::decode(kv.value)?);
+            count += 1;
+        }
+        if count != len {
+            return Err(Error::InvalidData);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, const N: usize> Decode<'a> for heapless::String<N> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let mut out = heapless::String::new();
+        out.push_str(<&str>::decode(el)?).map_err(|_| Error::CapacityExceeded)?;
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, T: Decode<'a>, const N: usize> Decode<'a> for heapless::Vec<T, N> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let DecodedElement::Array(a) = el else { return Err(Error::TypeMismatch) };
+        if a.len() > N {
+            return Err(Error::CapacityExceeded);
+        }
+        let mut items = array_of_len(el, a.len())?;
+        let mut out = heapless::Vec::new();
+        for _ in 0..a.len() {
+            out.push(next_element(&mut items)?).map_err(|_| Error::CapacityExceeded)?;
+        }
+        Ok(out)
+    }
+}
+
+/// If a key appears more than once, the last value wins
+#[cfg(feature = "heapless")]
+impl<'a, K, V, const N: usize> Decode<'a> for heapless::index_map::FnvIndexMap<K, V, N>
+where K: Decode<'a> + Eq + core::hash::Hash, V: Decode<'a>
+{
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let DecodedElement::Map(mut m) = el else { return Err(Error::TypeMismatch) };
+        if m.len() > N {
+            return Err(Error::CapacityExceeded);
+        }
+        m.reset();
+        let len = m.len();
+        let mut out = heapless::index_map::FnvIndexMap::new();
+        let mut count = 0;
+        for kv in m {
+            out.insert(K::decode(kv.key)?, V::decode(kv.value)?).map_err(|_| Error::CapacityExceeded)?;
+            count += 1;
+        }
+        if count != len {
+            return Err(Error::InvalidData);
+        }
+        Ok(out)
+    }
 }
 
 #[cfg(test)]
@@ -659,7 +1274,7 @@ mod tests {
     #[test]
     fn nil_decode() {
         let t: [u8; 1] = [0xC0];
-        if let Some(DecodedElement::Nil) = DecodedElement::from_slice_idx(&t, 0, false) {
+        if let Some(DecodedElement::Nil{..}) = DecodedElement::from_slice_idx(&t, 0, false) {
             
         } else {
             panic!()
@@ -672,20 +1287,34 @@ mod tests {
         let b: [u8; 1] = [0b11111110]; // fixint negative 2
         let c: [u8; 3] = [0xCD, 0x27, 0x3A]; // uint16 10042
         let d: [u8; 5] = [0xD2, 0xFF, 0xFF, 0xFF, 0xFC]; // int32 -4
-        assert_eq!(Some(DecodedElement::Int{size: 0, val: 8}), DecodedElement::from_slice_idx(&a, 0, false));
-        assert_eq!(Some(DecodedElement::Int{size: 0, val: -2}), DecodedElement::from_slice_idx(&b, 0, false));
-        assert_eq!(Some(DecodedElement::UInt{size: 2, val: 10042}), DecodedElement::from_slice_idx(&c, 0, false));
-        assert_eq!(Some(DecodedElement::Int{size: 4, val: -4}), DecodedElement::from_slice_idx(&d, 0, false));
+        assert_eq!(Some(DecodedElement::Int{size: 0, val: 8, raw: &a}), DecodedElement::from_slice_idx(&a, 0, false));
+        assert_eq!(Some(DecodedElement::Int{size: 0, val: -2, raw: &b}), DecodedElement::from_slice_idx(&b, 0, false));
+        assert_eq!(Some(DecodedElement::UInt{size: 2, val: 10042, raw: &c}), DecodedElement::from_slice_idx(&c, 0, false));
+        assert_eq!(Some(DecodedElement::Int{size: 4, val: -4, raw: &d}), DecodedElement::from_slice_idx(&d, 0, false));
     }
 
     #[test]
+    #[allow(clippy::approx_constant, clippy::excessive_precision)]
     fn float_decode() {
         let a_num: [u8; 4] = 3.1415926535_f32.to_be_bytes();
         let a: [u8; 5] = [0xCA, a_num[0], a_num[1], a_num[2], a_num[3]];
         let b_num: [u8; 8] = (22_f64/7_f64).to_be_bytes();
         let b: [u8; 9] = [0xCB, b_num[0], b_num[1], b_num[2], b_num[3], b_num[4], b_num[5], b_num[6], b_num[7]];
-        assert_eq!(Some(DecodedElement::Float(3.1415926535_f32)), DecodedElement::from_slice_idx(&a, 0, false));
-        assert_eq!(Some(DecodedElement::Double(22_f64/7_f64)), DecodedElement::from_slice_idx(&b, 0, false));
+        assert_eq!(Some(DecodedElement::Float{val: 3.1415926535_f32, raw: &a}), DecodedElement::from_slice_idx(&a, 0, false));
+        assert_eq!(Some(DecodedElement::Double{val: 22_f64/7_f64, raw: &b}), DecodedElement::from_slice_idx(&b, 0, false));
+    }
+
+    #[test]
+    fn scalar_raw_bytes() {
+        // [str 8 "hi", bin 8 [1], fixext 1 (5, [0xAA]), uint 16 1000, nil, false, float 32 1.5]
+        let t: [u8; 23] = [0x97, 0xD9, 0x02, b'h', b'i', 0xC4, 0x01, 0x01, 0xD4, 0x05, 0xAA, 0xCD, 0x03, 0xE8,
+                           0xC0, 0xC2, 0xCA, 0x3F, 0xC0, 0x00, 0x00, 0xC0, 0xC0];
+        let Some(DecodedElement::Array(a)) = DecodedElement::from_slice_idx(&t, 0, false) else { panic!() };
+        let raws: Vec<&[u8]> = a.map(|el| el.raw_bytes()).collect();
+        assert_eq!(vec![&t[1..5], &t[5..8], &t[8..11], &t[11..14], &t[14..15], &t[15..16], &t[16..21]], raws);
+        // The same bytes, not a copy of them
+        assert_eq!(t[11..].as_ptr(), raws[3].as_ptr());
+        assert_eq!(&t[21..22], DecodedElement::from_slice_idx(&t, 21, false).unwrap().raw_bytes());
     }
 
     #[test]
@@ -696,4 +1325,169 @@ mod tests {
         assert_eq!(None, DecodedElement::from_slice_idx(&a, 0, false));
         assert_eq!(None, DecodedElement::from_slice_idx(&b, 0, false));
     }
+
+    #[test]
+    fn nested_byte_size() {
+        // [[1, 2], 1000, [[]]]
+        let t: [u8; 10] = [0x93, 0x92, 0x01, 0x02, 0xCD, 0x03, 0xE8, 0x91, 0x90, 0xC0];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        assert_eq!(9, el.byte_size());
+        if let DecodedElement::Array(mut a) = el {
+            let sizes: Vec<usize> = a.map(|e| e.byte_size()).collect();
+            assert_eq!(vec![3, 3, 2], sizes);
+            assert_eq!(Some(DecodedElement::UInt{size: 2, val: 1000, raw: &t[4..7]}), a.get_element(1));
+            assert_eq!(None, a.get_element(3));
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn byte_size_is_stored() {
+        // [[1, 2], 1000, [[]]] and {1: [2]}
+        let t: [u8; 14] = [0x93, 0x92, 0x01, 0x02, 0xCD, 0x03, 0xE8, 0x91, 0x90, 0xC0, 0x81, 0x01, 0x91, 0x02];
+        let Some(DecodedElement::Array(mut a)) = DecodedElement::from_slice_idx(&t, 0, false) else { panic!() };
+        assert_eq!(None, a.size);
+        assert_eq!(9, a.byte_size());
+        assert_eq!(Some(9), a.size);
+        let Some(DecodedElement::Map(mut m)) = DecodedElement::from_slice_idx(&t, 10, false) else { panic!() };
+        assert_eq!(None, m.size);
+        assert_eq!(4, m.byte_size());
+        assert_eq!(Some(4), m.size);
+    }
+
+    #[test]
+    fn skip_elements() {
+        // [bin8 with 200 bytes, "abc", [[]]], nil
+        let mut t = vec![0x93, 0xC4, 200];
+        t.extend_from_slice(&[0xAA; 200]);
+        t.extend_from_slice(&[0xA3, b'a', b'b', b'c', 0x91, 0x90, 0xC0]);
+        assert_eq!(Ok(t.len() - 1), skip(&t, 0, false));
+        assert_eq!(Ok(203), skip(&t, 1, false));
+        // Strings aren't checked for valid UTF-8
+        assert_eq!(Ok(3), skip(&[0xA2, 0xFF, 0xFF], 0, false));
+        // Missing elements and truncated strings are errors
+        assert_eq!(Err(Error::InvalidData), skip(&[0x92, 0x01], 0, false));
+        assert_eq!(Err(Error::InvalidData), skip(&[0xA3, b'a'], 0, false));
+    }
+
+    #[test]
+    fn probe_partial() {
+        // [1, "abc", {b"": 1.5}]
+        let t = [0x93, 0x01, 0xA3, b'a', b'b', b'c', 0x81, 0xC4, 0x00, 0xCB, 0x3F, 0xF8, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Ok(Probe::Complete(t.len())), probe(&t, false));
+        let needed: Vec<usize> = (0..t.len()).map(|n| match probe(&t[..n], false) {
+            Ok(Probe::Incomplete(need)) => need,
+            other => panic!("{} bytes: {:?}", n, other)
+        }).collect();
+        assert_eq!(vec![1, 2, 3, 6, 6, 6, 7, 8, 9, 10, 18, 18, 18, 18, 18, 18, 18, 18], needed);
+        // A length in the header is enough to know how much more to wait for
+        assert_eq!(Ok(Probe::Incomplete(3 + 300)), probe(&[0xC5, 0x01, 0x2C, 0x00], false));
+        assert_eq!(Err(Error::InvalidData), probe(&[0x92, 0xC1], false));
+    }
+
+    #[test]
+    fn skip_deep_nesting() {
+        let mut t = vec![0x91_u8; 100_000];
+        t.push(0x01);
+        assert_eq!(Ok(t.len()), skip(&t, 0, false));
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        assert_eq!(t.len(), el.byte_size());
+        assert!(el.is_complete());
+        t.pop();
+        assert_eq!(Err(Error::InvalidData), skip(&t, 0, false));
+        // Truncated containers report the size of what is there, without recursing
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        assert_eq!(t.len(), el.byte_size());
+        assert!(!el.is_complete());
+    }
+
+    /// Records the events from `walk` as text
+    struct Recorder(Vec<String>);
+
+    impl<'a> Visitor<'a> for Recorder {
+        fn visit_int(&mut self, val: i64) { self.0.push(format!("int {}", val)); }
+        fn visit_str(&mut self, val: &'a str) { self.0.push(format!("str {}", val)); }
+        fn enter_array(&mut self, len: usize) { self.0.push(format!("[{}", len)); }
+        fn exit_array(&mut self) { self.0.push("]".into()); }
+        fn enter_map(&mut self, len: usize) { self.0.push(format!("{{{}", len)); }
+        fn map_key(&mut self) { self.0.push("key".into()); }
+        fn map_value(&mut self) { self.0.push("value".into()); }
+        fn exit_map(&mut self) { self.0.push("}".into()); }
+    }
+
+    #[test]
+    fn walk_nested() {
+        // {"a": [1, []], "b": 2} followed by a byte that isn't part of it
+        let t: [u8; 11] = [0x82, 0xA1, b'a', 0x92, 0x01, 0x90, 0xA1, b'b', 0x02, 0xC0, 0xC0];
+        let mut r = Recorder(Vec::new());
+        assert_eq!(Ok(9), walk(&t, &mut r, false));
+        assert_eq!(vec!["{2", "key", "str a", "value", "[2", "int 1", "[0", "]", "]",
+                        "key", "str b", "value", "int 2", "}"], r.0);
+    }
+
+    #[test]
+    fn decode_types() {
+        // [300, -2, "hi", b"\x01", nil, [1.5, 2]]
+        let t = [0x96, 0xCD, 0x01, 0x2C, 0xFE, 0xA2, b'h', b'i', 0xC4, 0x01, 0x01, 0xC0,
+                 0x92, 0xCA, 0x3F, 0xC0, 0x00, 0x00, 0x02];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        type Row<'a> = (u16, i8, &'a str, &'a [u8], Option<bool>, [f64; 2]);
+        assert_eq!(Ok((300, -2, "hi", &[1_u8][..], None, [1.5, 2.0])), Row::decode(el));
+        // Wrong types, out of range ints and the wrong number of elements are mismatches
+        assert_eq!(Err(Error::TypeMismatch), <(u8, i8, &str, &[u8], Option<bool>, [f64; 2])>::decode(el));
+        assert_eq!(Err(Error::TypeMismatch), <(u16, i8, &[u8], &[u8], Option<bool>, [f64; 2])>::decode(el));
+        assert_eq!(Err(Error::TypeMismatch), <(u16, i8, &str)>::decode(el));
+        let nested = DecodedElement::from_slice_idx(&t, 12, false).unwrap();
+        assert_eq!(Ok(vec![1.5_f32, 2.0]), Vec::<f32>::decode(nested));
+        assert_eq!(Err(Error::TypeMismatch), <[f64; 3]>::decode(nested));
+        // A double can be narrowed when no precision is lost, which includes NaN
+        let nan = [0xCB, 0x7F, 0xF8, 0, 0, 0, 0, 0, 0];
+        assert!(f32::decode(DecodedElement::from_slice_idx(&nan, 0, false).unwrap()).unwrap().is_nan());
+        let tenth = [0xCB, 0x3F, 0xB9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9A];
+        assert_eq!(Err(Error::TypeMismatch), f32::decode(DecodedElement::from_slice_idx(&tenth, 0, false).unwrap()));
+        // A truncated array is invalid rather than the wrong type
+        let el = DecodedElement::from_slice_idx(&[0x92, 0x01], 0, false).unwrap();
+        assert_eq!(Err(Error::InvalidData), Vec::<u8>::decode(el));
+    }
+
+    #[test]
+    fn decode_map() {
+        use std::collections::BTreeMap;
+        // {"a": ["x"], "b": []}
+        let t = [0x82, 0xA1, b'a', 0x91, 0xA1, b'x', 0xA1, b'b', 0x90];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        let map = BTreeMap::<String, Vec<&str>>::decode(el).unwrap();
+        assert_eq!(vec!["x"], map["a"]);
+        assert!(map["b"].is_empty());
+        assert_eq!(Err(Error::TypeMismatch), BTreeMap::<String, u8>::decode(el));
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    fn heapless_round_trip() {
+        use crate::encode::{Encode, EncodeOptions};
+        use heapless::{index_map::FnvIndexMap, String, Vec};
+        let mut map = FnvIndexMap::<String<4>, Vec<u8, 3>, 2>::new();
+        map.insert(String::try_from("ab").unwrap(), Vec::from_slice(&[1, 2, 3]).unwrap()).unwrap();
+        map.insert(String::try_from("c").unwrap(), Vec::new()).unwrap();
+        let mut buf = [0_u8; 16];
+        let n = map.encode(&mut buf, 0, EncodeOptions::default()).unwrap();
+        assert_eq!([0x82, 0xA2, b'a', b'b', 0x93, 0x01, 0x02, 0x03, 0xA1, b'c', 0x90], buf[..n]);
+        let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+        assert_eq!(Ok(map), FnvIndexMap::decode(el));
+        // Anything bigger than the capacity is an error rather than a panic
+        assert_eq!(Err(Error::CapacityExceeded), FnvIndexMap::<String<4>, Vec<u8, 2>, 2>::decode(el));
+        assert_eq!(Err(Error::CapacityExceeded), FnvIndexMap::<String<1>, Vec<u8, 3>, 2>::decode(el));
+        assert_eq!(Err(Error::CapacityExceeded), <Vec<u8, 2>>::decode(DecodedElement::from_slice_idx(&[0x93, 1, 2, 3], 0, false).unwrap()));
+    }
+
+    #[test]
+    fn walk_errors() {
+        let mut r = Recorder(Vec::new());
+        // Array promises two elements but only has one
+        assert_eq!(Err(Error::InvalidData), walk(&[0x92, 0x01], &mut r, false));
+        let deep = [0x91_u8; MAX_DEPTH + 1];
+        assert_eq!(Err(Error::TooDeep), walk(&deep, &mut r, false));
+    }
 }
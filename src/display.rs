@@ -0,0 +1,286 @@
+// mod display
+
+use core::fmt;
+use super::decode::*;
+use super::error::*;
+
+/// Write a non-container element in the JSON-like form used by `Display`
+fn fmt_scalar(el: &DecodedElement, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match el {
+        DecodedElement::Nil{..} => write!(f, "null"),
+        DecodedElement::Int{val, ..} => write!(f, "{}", val),
+        DecodedElement::UInt{val, ..} => write!(f, "{}", val),
+        DecodedElement::Bool{val: b, ..} => write!(f, "{}", b),
+        DecodedElement::Float{val: x, ..} => write!(f, "{:?}", x),
+        DecodedElement::Double{val: x, ..} => write!(f, "{:?}", x),
+        DecodedElement::Str{val, ..} => write!(f, "{:?}", val),
+        DecodedElement::Bin{val, ..} => {
+            write!(f, "b\"")?;
+            for b in val.iter() {
+                write!(f, "{}", core::ascii::escape_default(*b))?;
+            }
+            write!(f, "\"")
+        },
+        DecodedElement::Ext{exttype, data, ..} => {
+            write!(f, "ext({}, ", exttype)?;
+            for b in data.iter() {
+                write!(f, "{:02x}", b)?;
+            }
+            write!(f, ")")
+        },
+        // Containers go through `Printer` so that they don't recurse
+        DecodedElement::Array(_) | DecodedElement::Map(_) => Ok(())
+    }
+}
+
+/// Prints the elements of a container as `walk` visits them
+struct Printer<'f, 'g> {
+    f: &'f mut fmt::Formatter<'g>,
+    pretty: bool,
+    depth: usize,
+    // Whether each open container has yet to print an element, and whether it is a map
+    first: [bool; MAX_DEPTH],
+    in_map: [bool; MAX_DEPTH],
+    result: fmt::Result,
+}
+
+impl<'f, 'g> Printer<'f, 'g> {
+    fn write(&mut self, args: fmt::Arguments) {
+        if self.result.is_ok() {
+            self.result = self.f.write_fmt(args);
+        }
+    }
+    /// Start a new line at the current depth, in pretty mode
+    fn newline(&mut self) {
+        if self.pretty {
+            self.write(format_args!("\n{:1$}", "", self.depth * 2));
+        }
+    }
+    /// Separate an array element or map entry from the one before it
+    fn separate(&mut self) {
+        let d = self.depth - 1;
+        if self.first[d] {
+            self.first[d] = false;
+        } else if self.pretty {
+            self.write(format_args!(","));
+        } else {
+            self.write(format_args!(", "));
+        }
+        self.newline();
+    }
+    fn enter(&mut self, open: &str, is_map: bool) {
+        self.write(format_args!("{}", open));
+        self.first[self.depth] = true;
+        self.in_map[self.depth] = is_map;
+        self.depth += 1;
+    }
+    fn exit(&mut self, close: &str) {
+        self.depth -= 1;
+        if !self.first[self.depth] {
+            self.newline();
+        }
+        self.write(format_args!("{}", close));
+    }
+}
+
+impl<'a, 'f, 'g> Visitor<'a> for Printer<'f, 'g> {
+    fn visit_element(&mut self, _idx: usize, el: &DecodedElement<'a>) {
+        // Map entries are separated before their key instead
+        if self.depth > 0 && !self.in_map[self.depth-1] {
+            self.separate();
+        }
+        if self.result.is_ok() {
+            self.result = fmt_scalar(el, self.f);
+        }
+    }
+    fn enter_array(&mut self, _len: usize) { self.enter("[", false); }
+    fn exit_array(&mut self) { self.exit("]"); }
+    fn enter_map(&mut self, _len: usize) { self.enter("{", true); }
+    fn map_key(&mut self) { self.separate(); }
+    fn map_value(&mut self) { self.write(format_args!(": ")); }
+    fn exit_map(&mut self) { self.exit("}"); }
+}
+
+/// Print the container at the start of `buf`
+fn fmt_container(buf: &[u8], local_endian_fields: bool, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let pretty = f.alternate();
+    let mut printer = Printer{
+        f,
+        pretty,
+        depth: 0,
+        first: [true; MAX_DEPTH],
+        in_map: [false; MAX_DEPTH],
+        result: Ok(()),
+    };
+    let walked = walk(buf, &mut printer, local_endian_fields);
+    printer.result?;
+    if walked.is_err() {
+        write!(f, " <invalid>")?;
+    }
+    Ok(())
+}
+
+/// A JSON-like view of the data, with `b"..."` for bin and `ext(type, hex)` for ext.
+///
+/// The alternate form (`{:#}`) spreads containers over indented lines.
+impl<'a> fmt::Display for DecodedElement<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Array(a) => {
+                let (buf, local_endian_fields) = a.source();
+                fmt_container(buf, local_endian_fields, f)
+            },
+            Self::Map(m) => {
+                let (buf, local_endian_fields) = m.source();
+                fmt_container(buf, local_endian_fields, f)
+            },
+            _ => fmt_scalar(self, f)
+        }
+    }
+}
+
+impl<'a> fmt::Debug for DecodedElement<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nil{..} => write!(f, "Nil"),
+            Self::Int{val, ..} => f.debug_tuple("Int").field(val).finish(),
+            Self::UInt{val, ..} => f.debug_tuple("UInt").field(val).finish(),
+            Self::Bool{val, ..} => f.debug_tuple("Bool").field(val).finish(),
+            Self::Float{val, ..} => f.debug_tuple("Float").field(val).finish(),
+            Self::Double{val, ..} => f.debug_tuple("Double").field(val).finish(),
+            Self::Str{val, ..} => f.debug_tuple("Str").field(val).finish(),
+            Self::Bin{..} => write!(f, "Bin({})", self),
+            Self::Ext{..} => write!(f, "Ext({})", self),
+            Self::Array(a) => fmt::Debug::fmt(a, f),
+            Self::Map(m) => fmt::Debug::fmt(m, f),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for ArrayDecoder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let el = DecodedElement::Array(*self);
+        if f.alternate() {
+            write!(f, "Array({:#})", el)
+        } else {
+            write!(f, "Array({})", el)
+        }
+    }
+}
+
+impl<'a> fmt::Debug for MapDecoder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let el = DecodedElement::Map(*self);
+        if f.alternate() {
+            write!(f, "Map({:#})", el)
+        } else {
+            write!(f, "Map({})", el)
+        }
+    }
+}
+
+/// Number of bytes of each element shown by `dump`
+const DUMP_BYTES: usize = 8;
+
+/// Writes a line for each element as `walk` visits them
+struct Dumper<'a, 'w, W: fmt::Write> {
+    buf: &'a [u8],
+    out: &'w mut W,
+    depth: usize,
+    result: fmt::Result,
+}
+
+impl<'a, 'w, W: fmt::Write> Dumper<'a, 'w, W> {
+    fn line(&mut self, idx: usize, el: &DecodedElement<'a>) -> fmt::Result {
+        // Show the header of containers, or the whole of anything else
+        let len = match el {
+            DecodedElement::Array(a) => 1 + a.header_size() as usize,
+            DecodedElement::Map(m) => 1 + m.header_size() as usize,
+            _ => el.byte_size(),
+        };
+        write!(self.out, "{:08x} ", idx)?;
+        for b in &self.buf[idx..idx + len.min(DUMP_BYTES)] {
+            write!(self.out, " {:02x}", b)?;
+        }
+        let more = if len > DUMP_BYTES { ".." } else { "" };
+        // Line the formats up in a column after the bytes, then indent them by depth
+        let pad = DUMP_BYTES * 3 + 2 - len.min(DUMP_BYTES) * 3 - more.len() + 2 + self.depth * 2;
+        write!(self.out, "{}{:2$}", more, "", pad)?;
+        write!(self.out, "{}", marker_name(self.buf[idx]))?;
+        match el {
+            DecodedElement::Array(a) => writeln!(self.out, " ({} elements)", a.len()),
+            DecodedElement::Map(m) => writeln!(self.out, " ({} entries)", m.len()),
+            _ => writeln!(self.out, " {}", el),
+        }
+    }
+}
+
+impl<'a, 'w, W: fmt::Write> Visitor<'a> for Dumper<'a, 'w, W> {
+    fn visit_element(&mut self, idx: usize, el: &DecodedElement<'a>) {
+        if self.result.is_ok() {
+            self.result = self.line(idx, el);
+        }
+    }
+    fn enter_array(&mut self, _len: usize) { self.depth += 1; }
+    fn exit_array(&mut self) { self.depth -= 1; }
+    fn enter_map(&mut self, _len: usize) { self.depth += 1; }
+    fn exit_map(&mut self) { self.depth -= 1; }
+}
+
+/// Write an annotated listing of the element at the start of `buf`, for debugging wire captures.
+///
+/// Each element gets a line with its byte offset, its first few bytes (just the header,
+/// for containers), its format and its value.  Elements inside containers are indented.
+///
+/// Returns the number of bytes the element took up.  If `out` fails, `Error::BufferTooSmall`
+/// is returned.
+pub fn dump<W: fmt::Write>(buf: &[u8], out: &mut W, local_endian_fields: bool) -> Result<usize> {
+    let mut dumper = Dumper{buf, out, depth: 0, result: Ok(())};
+    let walked = walk(buf, &mut dumper, local_endian_fields);
+    dumper.result.map_err(|_| Error::BufferTooSmall)?;
+    walked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // {"a": [1, b"\x00z"], "b": ext(5, 0a0b), "c": {}}
+    const MSG: [u8; 23] = [0x83, 0xA1, b'a', 0x92, 0x01, 0xC4, 0x02, 0x00, b'z',
+                           0xA1, b'b', 0xD5, 0x05, 0x0A, 0x0B, 0xA1, b'c', 0x80,
+                           0xC0, 0xC0, 0xC0, 0xC0, 0xC0];
+
+    #[test]
+    fn display_compact() {
+        let el = DecodedElement::from_slice_idx(&MSG, 0, false).unwrap();
+        assert_eq!(r#"{"a": [1, b"\x00z"], "b": ext(5, 0a0b), "c": {}}"#, format!("{}", el));
+        let s = DecodedElement::from_slice_idx(&[0xA2, b'h', b'"'], 0, false).unwrap();
+        assert_eq!(r#""h\"""#, format!("{}", s));
+    }
+
+    #[test]
+    fn display_pretty() {
+        let el = DecodedElement::from_slice_idx(&MSG, 0, false).unwrap();
+        let expected = "{\n  \"a\": [\n    1,\n    b\"\\x00z\"\n  ],\n  \"b\": ext(5, 0a0b),\n  \"c\": {}\n}";
+        assert_eq!(expected, format!("{:#}", el));
+    }
+
+    #[test]
+    fn debug_shows_data() {
+        let el = DecodedElement::from_slice_idx(&[0x92, 0x01, 0xC0], 0, false).unwrap();
+        assert_eq!("Array([1, null])", format!("{:?}", el));
+        let el = DecodedElement::from_slice_idx(&[0xCD, 0x27, 0x3A], 0, false).unwrap();
+        assert_eq!("UInt(10042)", format!("{:?}", el));
+    }
+
+    #[test]
+    fn dump_lines() {
+        let mut out = String::new();
+        assert_eq!(Ok(18), dump(&MSG, &mut out, false));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(9, lines.len());
+        assert_eq!("00000000  83                         fixmap (3 entries)", lines[0]);
+        assert_eq!("00000003  92                           fixarray (2 elements)", lines[2]);
+        assert_eq!("00000005  c4 02 00 7a                    bin 8 b\"\\x00z\"", lines[4]);
+        assert_eq!("0000000b  d5 05 0a 0b                  fixext 2 ext(5, 0a0b)", lines[6]);
+    }
+}
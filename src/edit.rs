@@ -0,0 +1,142 @@
+// mod edit
+
+use super::decode::*;
+use super::encode::*;
+use super::error::*;
+
+/// Where an edited message was written, and its new length
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Edited {
+    /// The message was patched in the original buffer
+    InPlace(usize),
+    /// The original buffer was too small, so the message was rebuilt in the output buffer
+    Rebuilt(usize),
+}
+
+/// Replace the element at `path` in the message occupying `buf[..len]` with `new`.
+///
+/// Container headers only count their elements, so nothing outside the replaced element
+/// has to change.  The bytes before and after it are kept as they are: if `buf` has room
+/// for the new message they are shifted in place, otherwise they are copied into `out`
+/// around the new element.  Either way, untouched elements are never re-encoded.
+///
+/// `new` is encoded in the unused end of `buf` if it fits there, and otherwise at the
+/// start of `out`, so an in-place edit only needs `out` when `buf` has less spare room
+/// than the new element takes.
+pub fn replace(buf: &mut [u8], len: usize, path: &[PathSegment], new: EncodedElement, out: &mut [u8], local_endian_fields: bool) -> Result<Edited> {
+    if len > buf.len() {
+        return Err(Error::InvalidData);
+    }
+    // Find the byte range of the element being replaced
+    let (start, end) = match DecodedElement::locate(&buf[..len], 0, path, local_endian_fields) {
+        Some((el, idx)) => (idx, idx + el.byte_size()),
+        None => return Err(Error::PathNotFound),
+    };
+    if end > len {
+        return Err(Error::InvalidData);
+    }
+    let options = EncodeOptions{local_endian_fields, ..Default::default()};
+    let (n, in_buf) = match new.try_write(&mut buf[len..], 0, options) {
+        Ok(n) => (n, true),
+        Err(Error::BufferTooSmall) => (new.try_write(out, 0, options)?, false),
+        Err(e) => return Err(e),
+    };
+    let new_len = len - (end - start) + n;
+    if in_buf {
+        // [old element][tail][new element] becomes [new element][tail]
+        buf[start..len + n].rotate_right(n);
+        buf.copy_within(start + n + (end - start)..len + n, start + n);
+        Ok(Edited::InPlace(new_len))
+    } else if new_len <= buf.len() {
+        // Shift the tail to make (or close) room, then copy the new element over
+        buf.copy_within(end..len, start + n);
+        buf[start..start + n].copy_from_slice(&out[..n]);
+        Ok(Edited::InPlace(new_len))
+    } else if new_len <= out.len() {
+        out.copy_within(..n, start);
+        out[..start].copy_from_slice(&buf[..start]);
+        out[start + n..new_len].copy_from_slice(&buf[end..len]);
+        Ok(Edited::Rebuilt(new_len))
+    } else {
+        Err(Error::BufferTooSmall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // {"a": fixint 5, "b": [fixint 1, fixint 2]}
+    const MSG: [u8; 9] = [0x82, 0xA1, b'a', 0x05, 0xA1, b'b', 0x92, 0x01, 0x02];
+
+    #[test]
+    fn replace_same_size() {
+        let mut buf = MSG;
+        let mut out = [0_u8; 9];
+        let r = replace(&mut buf, 9, &[PathSegment::Key("a")], EncodedElement::Int(6), &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(9)), r);
+        assert_eq!([0x82, 0xA1, b'a', 0x06, 0xA1, b'b', 0x92, 0x01, 0x02], buf);
+    }
+
+    #[test]
+    fn replace_grow_in_place() {
+        let mut buf = [0_u8; 16];
+        buf[..9].copy_from_slice(&MSG);
+        let mut out = [0_u8; 16];
+        let path = [PathSegment::Key("b"), PathSegment::Index(0)];
+        let r = replace(&mut buf, 9, &path, EncodedElement::UInt(1000), &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(11)), r);
+        assert_eq!([0x82, 0xA1, b'a', 0x05, 0xA1, b'b', 0x92, 0xCD, 0x03, 0xE8, 0x02], buf[..11]);
+    }
+
+    #[test]
+    fn replace_rebuild() {
+        let mut buf = MSG;
+        let mut out = [0_u8; 16];
+        let r = replace(&mut buf, 9, &[PathSegment::Key("a")], EncodedElement::UInt(1000), &mut out, false);
+        assert_eq!(Ok(Edited::Rebuilt(11)), r);
+        assert_eq!([0x82, 0xA1, b'a', 0xCD, 0x03, 0xE8, 0xA1, b'b', 0x92, 0x01, 0x02], out[..11]);
+        // The original is left alone
+        assert_eq!(MSG, buf);
+    }
+
+    #[test]
+    fn replace_string() {
+        let mut buf = [0_u8; 16];
+        buf[..9].copy_from_slice(&MSG);
+        let mut out = [0_u8; 16];
+        let r = replace(&mut buf, 9, &[PathSegment::Key("a")], EncodedElement::Str("ok"), &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(11)), r);
+        assert_eq!([0x82, 0xA1, b'a', 0xA2, b'o', b'k', 0xA1, b'b', 0x92, 0x01, 0x02], buf[..11]);
+    }
+
+    #[test]
+    fn replace_small_out() {
+        // [fixint 1, fixint 2] with room to spare in buf, and almost none in out
+        let mut buf = [0_u8; 10];
+        buf[..3].copy_from_slice(&[0x92, 0x01, 0x02]);
+        let mut out = [0_u8; 2];
+        let r = replace(&mut buf, 3, &[PathSegment::Index(0)], EncodedElement::Int(7), &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(3)), r);
+        let r = replace(&mut buf, 3, &[PathSegment::Index(0)], EncodedElement::UInt(1000), &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(5)), r);
+        assert_eq!([0x92, 0xCD, 0x03, 0xE8, 0x02], buf[..5]);
+        // Too big for both, and the message is left alone
+        let r = replace(&mut buf, 5, &[PathSegment::Index(1)], EncodedElement::Str("abcdef"), &mut out, false);
+        assert_eq!(Err(Error::BufferTooSmall), r);
+        assert_eq!([0x92, 0xCD, 0x03, 0xE8, 0x02], buf[..5]);
+        // With no spare room in buf, out only has to hold the new element
+        let mut buf = [0x92, 0x01, 0x02];
+        let mut out = [0_u8; 1];
+        let r = replace(&mut buf, 3, &[PathSegment::Index(1)], EncodedElement::Nil, &mut out, false);
+        assert_eq!(Ok(Edited::InPlace(3)), r);
+        assert_eq!([0x92, 0x01, 0xC0], buf);
+    }
+
+    #[test]
+    fn replace_missing_path() {
+        let mut buf = MSG;
+        let mut out = [0_u8; 16];
+        let r = replace(&mut buf, 9, &[PathSegment::Key("c")], EncodedElement::Nil, &mut out, false);
+        assert_eq!(Err(Error::PathNotFound), r);
+    }
+}
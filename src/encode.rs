@@ -1,408 +1,944 @@
 // mod encode
 
 use super::bytesize::*;
+use super::endian::*;
+use super::error::*;
+use super::decode::{ArrayDecoder, DecodedElement, MapDecoder};
+
+/// Width of the field used to hold an integer on the wire
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IntWidth {
+    /// Use the smallest representation that holds the value
+    Min,
+    /// Always use a field of this many bytes (1, 2, 4 or 8).  0 requests a fixint.
+    Fixed(UBytes),
+}
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum EncodedElement<'a> {
     Nil,
     Int(i64),
     UInt(u64),
+    /// An integer written with a specific width, failing if the value does not fit
+    SizedInt{width: IntWidth, val: i64},
+    SizedUInt{width: IntWidth, val: u64},
     Bool(bool),
     Bin(&'a [u8]),
     Float(f32),
     Double(f64),
     Str(&'a str),
     Ext{exttype: u8, data: &'a [u8]},
+    /// A str, bin or ext written with a length field of `header_size` bytes (1, 2 or 4),
+    /// failing if the length does not fit.  0 requests a fixstr or fixext.
+    SizedStr{header_size: UBytes, val: &'a str},
+    SizedBin{header_size: UBytes, val: &'a [u8]},
+    SizedExt{header_size: UBytes, exttype: u8, data: &'a [u8]},
     Array(&'a [EncodedElement<'a>]),
     Map(&'a [[EncodedElement<'a>; 2]]),
+    /// Re-encode a decoded array, keeping the header and integer widths it was decoded with
+    DecodedArray(ArrayDecoder<'a>),
+    /// Re-encode a decoded map, keeping the header and integer widths it was decoded with
+    DecodedMap(MapDecoder<'a>),
+    /// Bytes that are already MessagePack-encoded, copied verbatim
+    Raw(&'a [u8]),
 }
 
+/// Convert a decoded element back into one that can be encoded.
+///
+/// Integers and the headers of strs, bins, exts and containers keep the width they were
+/// decoded with, so forwarding a decoded message reproduces the same bytes.
+impl<'a> From<DecodedElement<'a>> for EncodedElement<'a> {
+    fn from(el: DecodedElement<'a>) -> Self {
+        match el {
+            DecodedElement::Nil{..} => Self::Nil,
+            DecodedElement::Int{size, val, ..} => Self::SizedInt{width: IntWidth::Fixed(size), val},
+            DecodedElement::UInt{size, val, ..} => Self::SizedUInt{width: IntWidth::Fixed(size), val},
+            DecodedElement::Bool{val: b, ..} => Self::Bool(b),
+            DecodedElement::Bin{header_size, val, ..} => Self::SizedBin{header_size, val},
+            DecodedElement::Float{val: f, ..} => Self::Float(f),
+            DecodedElement::Double{val: d, ..} => Self::Double(d),
+            DecodedElement::Str{header_size, val, ..} => Self::SizedStr{header_size, val},
+            DecodedElement::Array(a) => Self::DecodedArray(a),
+            DecodedElement::Map(m) => Self::DecodedMap(m),
+            DecodedElement::Ext{header_size, exttype, data, ..} => Self::SizedExt{header_size, exttype, data},
+        }
+    }
+}
+
+/// Write a marker byte followed by `bytes`, returning the number of bytes written
+fn write_marked(write_slice: &mut [u8], marker: u8, bytes: &[u8]) -> Result<usize> {
+    if write_slice.len() > bytes.len() {
+        write_slice[0] = marker;
+        write_slice[1..1+bytes.len()].copy_from_slice(bytes);
+        Ok(1 + bytes.len())
+    } else {
+        Err(Error::BufferTooSmall)
+    }
+}
+
+/// Write `payload` after a header of `header_size` bytes, returning the size of both
+fn write_payload(write_slice: &mut [u8], header_size: usize, payload: &[u8]) -> Result<usize> {
+    let end = header_size + payload.len();
+    if write_slice.len() < end {
+        return Err(Error::BufferTooSmall);
+    }
+    write_slice[header_size..end].copy_from_slice(payload);
+    Ok(end)
+}
+
+/// Write an ext's type and data after a header of `header_size` bytes, returning the size of all three
+fn write_ext_body(write_slice: &mut [u8], header_size: usize, exttype: u8, data: &[u8]) -> Result<usize> {
+    // The type follows the length
+    *write_slice.get_mut(header_size).ok_or(Error::BufferTooSmall)? = exttype;
+    write_payload(write_slice, header_size + 1, data)
+}
+
+/// Write a signed integer using the given width.  A value that doesn't fit in a fixed width is `InvalidData`.
+fn write_int(write_slice: &mut [u8], width: IntWidth, i: i64, local_endian_fields: bool) -> Result<usize> {
+    let order = ByteOrder::from_local_endian(local_endian_fields);
+    let size = match width {
+        // Non-negative values take the shortest form of the same value as a uint, which is
+        // what the spec recommends and other implementations send
+        IntWidth::Min if i >= 0 => return write_uint(write_slice, IntWidth::Min, i as u64, local_endian_fields),
+        IntWidth::Min if i >= -32 => 0,
+        IntWidth::Min => get_min_size_signed(i),
+        IntWidth::Fixed(s) => s,
+    };
+    match size {
+        // Positive and negative fixints are both just the two's complement byte
+        0 if (-32..=127).contains(&i) => write_marked(write_slice, i as u8, &[]),
+        1 if i8::try_from(i).is_ok() => write_marked(write_slice, 0xD0, &order.bytes(i as i8)),
+        2 if i16::try_from(i).is_ok() => write_marked(write_slice, 0xD1, &order.bytes(i as i16)),
+        4 if i32::try_from(i).is_ok() => write_marked(write_slice, 0xD2, &order.bytes(i as i32)),
+        8 => write_marked(write_slice, 0xD3, &order.bytes(i)),
+        _ => Err(Error::InvalidData)
+    }
+}
+
+/// Write an unsigned integer using the given width.  A value that doesn't fit in a fixed width is `InvalidData`.
+fn write_uint(write_slice: &mut [u8], width: IntWidth, i: u64, local_endian_fields: bool) -> Result<usize> {
+    let order = ByteOrder::from_local_endian(local_endian_fields);
+    let size = match width {
+        IntWidth::Min if i <= 127 => 0,
+        IntWidth::Min => get_min_size_unsigned(i),
+        IntWidth::Fixed(s) => s,
+    };
+    match size {
+        0 if i <= 127 => write_marked(write_slice, i as u8, &[]),
+        1 if u8::try_from(i).is_ok() => write_marked(write_slice, 0xCC, &[i as u8]),
+        2 if u16::try_from(i).is_ok() => write_marked(write_slice, 0xCD, &order.bytes(i as u16)),
+        4 if u32::try_from(i).is_ok() => write_marked(write_slice, 0xCE, &order.bytes(i as u32)),
+        8 => write_marked(write_slice, 0xCF, &order.bytes(i)),
+        _ => Err(Error::InvalidData)
+    }
+}
+
+/// The formats for one kind of element with a length: optionally a fix format that holds
+/// lengths up to a limit in its marker, then formats with 8, 16 and 32-bit length fields
+struct LenFormats {
+    fix: Option<(u8, usize)>,
+    len8: Option<u8>,
+    len16: u8,
+    len32: u8,
+}
+
+const STR_FORMATS: LenFormats = LenFormats{fix: Some((0xA0, 31)), len8: Some(0xD9), len16: 0xDA, len32: 0xDB};
+const BIN_FORMATS: LenFormats = LenFormats{fix: None, len8: Some(0xC4), len16: 0xC5, len32: 0xC6};
+// Fixext is picked by size rather than holding a length, so it isn't listed here
+const EXT_FORMATS: LenFormats = LenFormats{fix: None, len8: Some(0xC7), len16: 0xC8, len32: 0xC9};
+const ARRAY_FORMATS: LenFormats = LenFormats{fix: Some((0x90, 15)), len8: None, len16: 0xDC, len32: 0xDD};
+const MAP_FORMATS: LenFormats = LenFormats{fix: Some((0x80, 15)), len8: None, len16: 0xDE, len32: 0xDF};
+
+/// Write the header for `len` bytes or elements, in the shortest of `formats` that holds it
+fn write_len_header(write_slice: &mut [u8], formats: &LenFormats, len: usize) -> Result<usize> {
+    match (formats.fix, formats.len8) {
+        (Some((marker, max)), _) if len <= max => write_marked(write_slice, marker + len as u8, &[]),
+        (_, Some(marker)) if len <= u8::MAX as usize => write_marked(write_slice, marker, &[len as u8]),
+        _ if len <= u16::MAX as usize => write_marked(write_slice, formats.len16, &ByteOrder::Big.bytes(len as u16)),
+        _ if len <= u32::MAX as usize => write_marked(write_slice, formats.len32, &ByteOrder::Big.bytes(len as u32)),
+        _ => Err(Error::LengthOverflow)
+    }
+}
+
+/// Write the header of a str of `len` bytes, for a body that is written after it separately
+pub fn write_str_header(slice: &mut [u8], idx: usize, len: usize) -> Result<usize> {
+    write_len_header(slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?, &STR_FORMATS, len)
+}
+
+/// Write the header of a bin of `len` bytes, for a body that is written after it separately
+pub fn write_bin_header(slice: &mut [u8], idx: usize, len: usize) -> Result<usize> {
+    write_len_header(slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?, &BIN_FORMATS, len)
+}
+
+/// Write the header for `count` bytes or elements with a size field of `header_size` bytes,
+/// where 0 is the fix format
+fn write_sized_len_header(write_slice: &mut [u8], formats: &LenFormats, count: usize, header_size: UBytes) -> Result<usize> {
+    match (header_size, formats.fix, formats.len8) {
+        (0, Some((marker, max)), _) if count <= max => write_marked(write_slice, marker + count as u8, &[]),
+        (1, _, Some(marker)) if count <= u8::MAX as usize => write_marked(write_slice, marker, &[count as u8]),
+        (2, _, _) if count <= u16::MAX as usize => write_marked(write_slice, formats.len16, &ByteOrder::Big.bytes(count as u16)),
+        (4, _, _) if count <= u32::MAX as usize => write_marked(write_slice, formats.len32, &ByteOrder::Big.bytes(count as u32)),
+        _ => Err(Error::InvalidData)
+    }
+}
+
+/// Choices about how elements are written, for `EncodedElement::write_with`
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct EncodeOptions {
+    /// Write numbers in the byte order of this machine (see `ByteOrder`)
+    pub local_endian_fields: bool,
+    /// Write doubles as float 32 when that loses nothing
+    pub narrow_floats: bool,
+    /// Write floats with integral values as ints, for peers that treat all numbers alike.
+    /// -0.0 and values outside the range of i64 and u64 stay floats.
+    pub integral_floats_as_ints: bool,
+    /// Write every NaN as the quiet NaN with no payload, so equal messages have equal bytes
+    pub canonical_nan: bool,
+}
+
+/// The NaNs written when `canonical_nan` is set
+const CANONICAL_NAN_32: u32 = 0x7FC0_0000;
+const CANONICAL_NAN_64: u64 = 0x7FF8_0000_0000_0000;
+
 impl<'a> EncodedElement<'a> {
-    /// Write a MessagePack element into `slice` beginning at `idx`, using the most efficient representation
-    pub fn write_to(self, slice: &'a mut [u8], idx: usize, local_endian_fields: bool) -> usize {
-        if idx >= slice.len() {
-            return 0
+    /// Apply the float options to this element
+    fn convert_float(self, options: EncodeOptions) -> Self {
+        let d = match self {
+            Self::Float(f) => f as f64,
+            Self::Double(d) => d,
+            _ => return self
         };
-        let (_, write_slice) = slice.split_at_mut(idx);
-        // Switch depending on what kind of element this is
+        if options.integral_floats_as_ints && !(d == 0.0 && d.is_sign_negative()) {
+            // The casts saturate, so a value out of range doesn't survive the round trip
+            if d >= 0.0 && (d as u64) as f64 == d && d < u64::MAX as f64 {
+                return Self::UInt(d as u64);
+            } else if d < 0.0 && (d as i64) as f64 == d {
+                return Self::Int(d as i64);
+            }
+        }
+        if d.is_nan() && options.canonical_nan {
+            return match self {
+                Self::Double(_) if !options.narrow_floats => Self::Double(f64::from_bits(CANONICAL_NAN_64)),
+                _ => Self::Float(f32::from_bits(CANONICAL_NAN_32)),
+            };
+        }
         match self {
-            Self::Nil => {
-                write_slice[0] = 0xC0;
-                1
+            Self::Double(d) if options.narrow_floats && (d as f32) as f64 == d => Self::Float(d as f32),
+            _ => self
+        }
+    }
+    /// Wrap pre-encoded bytes after checking that they hold exactly one complete element
+    pub fn raw_checked(bytes: &'a [u8], local_endian_fields: bool) -> Option<Self> {
+        let el = DecodedElement::from_slice_idx(bytes, 0, local_endian_fields)?;
+        if el.byte_size() == bytes.len() && el.is_complete() {
+            Some(Self::Raw(bytes))
+        } else {
+            None
+        }
+    }
+    /// Write a MessagePack element into `slice` beginning at `idx`, using the most efficient representation.
+    ///
+    /// Returns the number of bytes written, or 0 if the element could not be written (see
+    /// `try_write` for the reasons).
+    pub fn write_to(self, slice: &'a mut [u8], idx: usize, local_endian_fields: bool) -> usize {
+        self.write_with(slice, idx, EncodeOptions{local_endian_fields, ..Default::default()})
+    }
+    /// Write a MessagePack element into `slice` beginning at `idx`, as `write_to` does, with
+    /// the given options applied to it and everything inside it
+    pub fn write_with(self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> usize {
+        self.try_write(slice, idx, options).unwrap_or(0)
+    }
+    /// Write a MessagePack element into `slice` beginning at `idx`, returning the number of bytes written.
+    ///
+    /// Fails with `Error::BufferTooSmall` if the element doesn't fit, `Error::LengthOverflow`
+    /// if a str, bin, ext, array or map is longer than a 32-bit length field can describe,
+    /// and `Error::InvalidData` if an integer doesn't fit the width it asks for or a raw
+    /// element is empty.  Part of the element may have been written when it fails.
+    pub fn try_write(self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        let write_slice = slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?;
+        let local_endian_fields = options.local_endian_fields;
+        let order = ByteOrder::from_local_endian(local_endian_fields);
+        // Switch depending on what kind of element this is
+        match self.convert_float(options) {
+            Self::Nil => write_marked(write_slice, 0xC0, &[]),
+            Self::Int(i) => write_int(write_slice, IntWidth::Min, i, local_endian_fields),
+            Self::SizedInt{width, val} => write_int(write_slice, width, val, local_endian_fields),
+            Self::UInt(i) => write_uint(write_slice, IntWidth::Min, i, local_endian_fields),
+            Self::SizedUInt{width, val} => write_uint(write_slice, width, val, local_endian_fields),
+            Self::Bool(b) => write_marked(write_slice, if b { 0xC3 } else { 0xC2 }, &[]),
+            Self::Float(f) => write_marked(write_slice, 0xCA, &order.bytes(f)),
+            Self::Double(d) => write_marked(write_slice, 0xCB, &order.bytes(d)),
+            Self::Bin(data) => {
+                let header_size = write_len_header(write_slice, &BIN_FORMATS, data.len())?;
+                write_payload(write_slice, header_size, data)
             },
-            Self::Int(i) => {
-                // Get the smallest representation
-                match get_min_size_signed(i) {
-                    1 => {
-                        // This number fits in one byte.  Question is, does it fit in a fixint?
-                        // for positive fixint, there is no restriction other than having it be positive
-                        if i > 0 {
-                            write_slice[0] = i as u8;
-                            1
-                        } else if i < 0 && i > -32 {
-                            // Use negative fixint
-                            write_slice[0] = (256_i16 + i as i16) as u8; // turns negative i8 into positive u8
-                            1
-                        } else {
-                            // No fixint
-                            if write_slice.len() >= 2 {
-                                write_slice[0] = 0xD0;
-                                write_slice[1] = (256_i16 + i as i16) as u8; // If we got here the number has to be negative
-                                2
-                            } else {
-                                0
-                            }
-                        }
-                    },
-                    2 => {
-                        if write_slice.len() >= 3 {
-                            write_slice[0] = 0xD1;
-                            let bytes = if local_endian_fields {
-                                (i as i16).to_ne_bytes()
-                            } else {
-                                (i as i16).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            3
-                        } else {
-                            0
-                        }
-                    },
-                    4 => {
-                        if write_slice.len() >= 5 {
-                            write_slice[0] = 0xD2;
-                            let bytes = if local_endian_fields {
-                                (i as i32).to_ne_bytes()
-                            } else {
-                                (i as i32).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            write_slice[3] = bytes[2];
-                            write_slice[4] = bytes[3];
-                            5
-                        } else {
-                            0
-                        }
-                    },
-                    _ => {
-                        if write_slice.len() >= 9 {
-                            write_slice[0] = 0xD3;
-                            let bytes = if local_endian_fields {
-                                (i as i64).to_ne_bytes()
-                            } else {
-                                (i as i64).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            write_slice[3] = bytes[2];
-                            write_slice[4] = bytes[3];
-                            write_slice[5] = bytes[4];
-                            write_slice[6] = bytes[5];
-                            write_slice[7] = bytes[6];
-                            write_slice[8] = bytes[7];
-                            9
-                        } else {
-                            0
-                        }
-                    }
-                }
+            Self::Str(s) => {
+                let header_size = write_len_header(write_slice, &STR_FORMATS, s.len())?;
+                write_payload(write_slice, header_size, s.as_bytes())
             },
-            Self::UInt(i) => {
-                match get_min_size_unsigned(i) {
-                    1 => {
-                        // No fixint
-                        if write_slice.len() >= 2 {
-                            write_slice[0] = 0xCC;
-                            write_slice[1] = i as u8;
-                            2
-                        } else {
-                            0
-                        }
-                    },
-                    2 => {
-                        if write_slice.len() >= 3 {
-                            write_slice[0] = 0xCD;
-                            let bytes = if local_endian_fields {
-                                (i as u16).to_ne_bytes()
-                            } else {
-                                (i as u16).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            3
-                        } else {
-                            0
-                        }
-                    },
-                    4 => {
-                        if write_slice.len() >= 5 {
-                            write_slice[0] = 0xCE;
-                            let bytes = if local_endian_fields {
-                                (i as u32).to_ne_bytes()
-                            } else {
-                                (i as u32).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            write_slice[3] = bytes[2];
-                            write_slice[4] = bytes[3];
-                            5
-                        } else {
-                            0
-                        }
-                    },
-                    _ => {
-                        if write_slice.len() >= 9 {
-                            write_slice[0] = 0xCF;
-                            let bytes = if local_endian_fields {
-                                (i as u64).to_ne_bytes()
-                            } else {
-                                (i as u64).to_be_bytes()
-                            };
-                            write_slice[1] = bytes[0];
-                            write_slice[2] = bytes[1];
-                            write_slice[3] = bytes[2];
-                            write_slice[4] = bytes[3];
-                            write_slice[5] = bytes[4];
-                            write_slice[6] = bytes[5];
-                            write_slice[7] = bytes[6];
-                            write_slice[8] = bytes[7];
-                            9
-                        } else {
-                            0
-                        }
-                    }
-                }
+            Self::Ext{exttype, data} => {
+                // Fixext is only used when the data is exactly one of its sizes
+                let header_size = match data.len() {
+                    1 | 2 | 4 | 8 | 16 => write_marked(write_slice, 0xD4 + data.len().ilog2() as u8, &[])?,
+                    len => write_len_header(write_slice, &EXT_FORMATS, len)?,
+                };
+                write_ext_body(write_slice, header_size, exttype, data)
             },
-            Self::Bool(i) => {
-                if write_slice.len() >= 1 {
-                    write_slice[0] = if i { 0xC3 } else { 0xC2 };
-                    1
-                } else {
-                    0
-                }
+            Self::SizedStr{header_size, val} => {
+                let header_size = write_sized_len_header(write_slice, &STR_FORMATS, val.len(), header_size)?;
+                write_payload(write_slice, header_size, val.as_bytes())
             },
-            Self::Bin(i) => {
-                // Determine how big the size field needs to be first
-                let size_n = get_min_size_unsigned(i.len() as u64) as usize;
-                if write_slice.len() >= 1_usize + size_n + i.len() {
-                    match size_n {
-                        1 => {
-                            write_slice[0] = 0xC4;
-                            write_slice[1] = i.len() as u8;
-                            write_slice[2..2+i.len()].copy_from_slice(&i);
-                        },
-                        2 => {
-                            write_slice[0] = 0xC5;
-                            let size_bytes = (i.len() as u16).to_be_bytes();
-                            write_slice[1..3].copy_from_slice(&size_bytes);
-                            write_slice[3..3+i.len()].copy_from_slice(&i);
-                        },
-                        4 => {
-                            write_slice[0] = 0xC6;
-                            let size_bytes = (i.len() as u32).to_be_bytes();
-                            write_slice[1..5].copy_from_slice(&size_bytes);
-                            write_slice[5..5+i.len()].copy_from_slice(&i);
-                        },
-                        _ => {
-                            // Too big
-                            return 0;
-                        }
-                    };
-                    1_usize + size_n + i.len()
-                } else {
-                    0
-                }
+            Self::SizedBin{header_size, val} => {
+                let header_size = write_sized_len_header(write_slice, &BIN_FORMATS, val.len(), header_size)?;
+                write_payload(write_slice, header_size, val)
             },
-            Self::Float(i) => {
-                if write_slice.len() >= 5 {
-                    write_slice[0] = 0xCA;
-                    let bytes = if local_endian_fields {
-                        i.to_ne_bytes()
-                    } else {
-                        i.to_be_bytes()
-                    };
-                    write_slice[1..5].copy_from_slice(&bytes);
-                    5
-                } else {
-                    0
-                }
+            Self::SizedExt{header_size, exttype, data} => {
+                let header_size = match (header_size, data.len()) {
+                    (0, 1 | 2 | 4 | 8 | 16) => write_marked(write_slice, 0xD4 + data.len().ilog2() as u8, &[])?,
+                    (0, _) => return Err(Error::InvalidData),
+                    (size, len) => write_sized_len_header(write_slice, &EXT_FORMATS, len, size)?,
+                };
+                write_ext_body(write_slice, header_size, exttype, data)
             },
-            Self::Double(i) => {
-                if write_slice.len() >= 9 {
-                    write_slice[0] = 0xCB;
-                    let bytes = if local_endian_fields {
-                        i.to_ne_bytes()
-                    } else {
-                        i.to_be_bytes()
-                    };
-                    write_slice[1..9].copy_from_slice(&bytes);
-                    9
-                } else {
-                    0
+            Self::Array(arr) => {
+                // Write the header, then each element after the one before
+                let mut next = write_len_header(write_slice, &ARRAY_FORMATS, arr.len())?;
+                for el in arr {
+                    next += el.try_write(write_slice, next, options)?;
                 }
+                Ok(next)
             },
-            Self::Str(i) => {
-                let bytes = i.as_bytes();
-                let size_n = get_min_size_unsigned(bytes.len() as u64) as usize;
-                if write_slice.len() >= 1_usize + size_n + bytes.len() {
-                    match size_n {
-                        1 => {
-                            // String has an option for fixstr that is very compact, so try to use it
-                            if size_n < 31 {
-                                write_slice[0] = (bytes.len() as u8) + 0xA0;
-                                write_slice[1..bytes.len()].copy_from_slice(&bytes);
-                            } else {
-                                write_slice[0] = 0xD9;
-                                write_slice[1] = bytes.len() as u8;
-                                write_slice[2..2+bytes.len()].copy_from_slice(&bytes);
-                            }
-                        },
-                        2 => {
-                            write_slice[0] = 0xDA;
-                            let size_bytes = (bytes.len() as u16).to_be_bytes();
-                            write_slice[1..3].copy_from_slice(&size_bytes);
-                            write_slice[3..3+bytes.len()].copy_from_slice(&bytes);
-                        },
-                        4 => {
-                            write_slice[0] = 0xDB;
-                            let size_bytes = (bytes.len() as u32).to_be_bytes();
-                            write_slice[1..5].copy_from_slice(&size_bytes);
-                            write_slice[5..5+bytes.len()].copy_from_slice(&bytes);
-                        },
-                        _ => {
-                            // Too big
-                            return 0;
-                        }
-                    };
-                    1_usize + size_n + i.len()
-                } else {
-                    0
+            Self::Map(m) => {
+                let mut next = write_len_header(write_slice, &MAP_FORMATS, m.len())?;
+                for [key, value] in m {
+                    next += key.try_write(write_slice, next, options)?;
+                    next += value.try_write(write_slice, next, options)?;
                 }
+                Ok(next)
             },
-            Self::Ext{exttype: t, data: i} => {
-                const max_size: usize = (u32::MAX - 1) as usize;
-                match i.len() {
-                    // Not sure how fixext is supposed to be used, 
-                    // so for this purpose we only make fixext if the number
-                    // of bytes is exact.
-                    1 | 2 | 4 | 8 | 16 => {
-                        if write_slice.len() > 2 + i.len() {
-                            write_slice[0] = 0xD4 + (i.len().log2() as u8);
-                            write_slice[1] = t;
-                            write_slice[2..2+i.len()].copy_from_slice(i);
-                            3
-                        } else {
-                            0
-                        }
-                    },
-                    3..=max_size => {
-                        let size_n = get_min_size_unsigned(i.len() as u64) as usize;
-                        if write_slice.len() >= 3 + size_n + i.len() {
-                            write_slice[0] = 0xC7 + (size_n as u8 / 2);
-                            write_slice[1..1+size_n].copy_from_slice(&(i.len() as u64).to_be_bytes()[8-size_n..]); // big endian so take the last n bytes
-                            write_slice[1+size_n..1+size_n+i.len()].copy_from_slice(&i);
-                            1+size_n+i.len()
-                        } else {
-                            0
-                        }
-                    },
-                    _ => 0
+            Self::Raw(bytes) => {
+                if bytes.is_empty() {
+                    return Err(Error::InvalidData);
                 }
+                write_payload(write_slice, 0, bytes)
             },
-            Self::Array(arr) => {
-                // Arrays are encoded very simply
-                // First print the header byte, then just slice the slice and
-                // encode each element, checking for errors each time
-                let mut next: usize; // Byte index to write the next thing to
-                // Cutoffs for 16 and 32-bit sizes
-                const C16: usize = u16::MAX as usize;
-                const C32: usize = u32::MAX as usize;
-                match arr.len() {
-                    0 => return 0,
-                    1..=15 => {
-                        // Write fixarr header
-                        if write_slice.len() < 1 { return 0; }
-                        write_slice[0] = 0x90 + arr.len() as u8;
-                        next = 1;
-                    },
-                    16..=C16 => {
-                        // Write arr16 header
-                        if write_slice.len() < 3 { return 0; }
-                        write_slice[0] = 0xDC;
-                        write_slice[1..3].copy_from_slice(&(arr.len() as u16).to_be_bytes());
-                        next = 3;
-                    },
-                    C16..=C32 => {
-                        // Write arr32 header
-                        if write_slice.len() < 5 { return 0; }
-                        write_slice[0] = 0xDD;
-                        write_slice[1..5].copy_from_slice(&(arr.len() as u32).to_be_bytes());
-                        next = 5;
-                    },
-                    _ => return 0
-                };
-                // Write the elements, checking each time
+            Self::DecodedArray(mut arr) => {
+                // Write the header with the same width it was decoded with, then
+                // convert and write each element in turn
+                arr.reset();
+                let mut next = write_sized_len_header(write_slice, &ARRAY_FORMATS, arr.len(), arr.header_size())?;
                 for el in arr {
-                    let n = el.write_to(write_slice, next, local_endian_fields);
-                    if n != 0 {
-                        next += n;
-                    } else {
-                        return 0;
-                    }
+                    next += EncodedElement::from(el).try_write(write_slice, next, options)?;
                 }
-                next
+                Ok(next)
             },
-            Self::Map(m) => {
-                // This is more or less copypasta from the array decoder, except
-                // now when we write elements, we have to write both the key and value.
-                // Arrays are encoded very simply
-                // First print the header byte, then just slice the slice and
-                // encode each element, checking for errors each time
-                let mut next: usize; // Byte index to write the next thing to
-                // Cutoffs for 16 and 32-bit sizes
-                const C16: usize = u16::MAX as usize;
-                const C32: usize = u32::MAX as usize;
-                let elements = m.len() * 2;
-                match elements {
-                    0 => return 0,
-                    1..=15 => {
-                        // Write fixarr header
-                        if write_slice.len() < 1 { return 0; }
-                        write_slice[0] = 0x80 + elements as u8;
-                        next = 1;
-                    },
-                    16..=C16 => {
-                        // Write arr16 header
-                        if write_slice.len() < 3 { return 0; }
-                        write_slice[0] = 0xDC;
-                        write_slice[1..3].copy_from_slice(&(elements as u16).to_be_bytes());
-                        next = 3;
-                    },
-                    C16..=C32 => {
-                        // Write arr32 header
-                        if write_slice.len() < 5 { return 0; }
-                        write_slice[0] = 0xDD;
-                        write_slice[1..5].copy_from_slice(&(elements as u32).to_be_bytes());
-                        next = 5;
-                    },
-                    _ => return 0
-                };
-                // Write the elements, checking each time
-                for map in m {
-                    for kv in map {
-                        let n = kv.write_to(write_slice, next, local_endian_fields);
-                        if n != 0 {
-                            next += n;
-                        } else {
-                            return 0;
-                        }
-                    }
+            Self::DecodedMap(mut map) => {
+                map.reset();
+                let mut next = write_sized_len_header(write_slice, &MAP_FORMATS, map.len(), map.header_size())?;
+                for kv in map {
+                    next += EncodedElement::from(kv.key).try_write(write_slice, next, options)?;
+                    next += EncodedElement::from(kv.value).try_write(write_slice, next, options)?;
                 }
-                next
+                Ok(next)
+            }
+        }
+    }
+}
+
+/// A Rust value that can be written as MessagePack directly, without building an
+/// `EncodedElement` tree first.
+///
+/// Slices, arrays, tuples and `Vec`s are written as arrays, so `&[u8]` is an array of
+/// ints; wrap it in `EncodedElement::Bin` to write bin instead.  `None` is written as nil.
+pub trait Encode {
+    /// Write the value into `slice` beginning at `idx`, returning the number of bytes
+    /// written.  Fails in the same ways as `EncodedElement::try_write`.
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize>;
+}
+
+/// Write the items of an iterator as an array.
+///
+/// The header is written first from the iterator's `len()`, so an iterator that yields a
+/// different number of items fails with `Error::InvalidData`.
+pub fn encode_seq<I>(items: I, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize>
+where I: IntoIterator, I::IntoIter: ExactSizeIterator, I::Item: Encode
+{
+    let items = items.into_iter();
+    let len = items.len();
+    let write_slice = slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?;
+    let mut next = write_len_header(write_slice, &ARRAY_FORMATS, len)?;
+    let mut count = 0;
+    for item in items {
+        count += 1;
+        if count > len {
+            return Err(Error::InvalidData);
+        }
+        next += item.encode(write_slice, next, options)?;
+    }
+    if count != len {
+        return Err(Error::InvalidData);
+    }
+    Ok(next)
+}
+
+/// Write key-value pairs from an iterator as a map.
+///
+/// As with `encode_seq`, the iterator must yield exactly `len()` pairs.
+pub fn encode_map<K, V, I>(entries: I, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize>
+where I: IntoIterator<Item = (K, V)>, I::IntoIter: ExactSizeIterator, K: Encode, V: Encode
+{
+    let entries = entries.into_iter();
+    let len = entries.len();
+    encode_pairs(entries, len, slice, idx, options)
+}
+
+/// Write `len` key-value pairs as a map, for collections whose iterators don't know their
+/// length
+fn encode_pairs<K, V, I>(entries: I, len: usize, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize>
+where I: Iterator<Item = (K, V)>, K: Encode, V: Encode
+{
+    let write_slice = slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?;
+    let mut next = write_len_header(write_slice, &MAP_FORMATS, len)?;
+    let mut count = 0;
+    for (key, value) in entries {
+        count += 1;
+        if count > len {
+            return Err(Error::InvalidData);
+        }
+        next += key.encode(write_slice, next, options)?;
+        next += value.encode(write_slice, next, options)?;
+    }
+    if count != len {
+        return Err(Error::InvalidData);
+    }
+    Ok(next)
+}
+
+/// A byte buffer that can be resized, for encoding values of unknown size
+#[cfg(feature = "alloc")]
+trait Resizable: core::ops::DerefMut<Target = [u8]> {
+    /// Grow or shrink the buffer to `len` bytes, filling any new bytes with zeroes
+    fn resize_to(&mut self, len: usize);
+}
+
+#[cfg(feature = "alloc")]
+impl Resizable for alloc::vec::Vec<u8> {
+    fn resize_to(&mut self, len: usize) {
+        self.resize(len, 0);
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Resizable for bytes::BytesMut {
+    fn resize_to(&mut self, len: usize) {
+        self.resize(len, 0);
+    }
+}
+
+/// Encode `value` onto the end of `buf`, doubling the room for it until it fits
+#[cfg(feature = "alloc")]
+fn encode_growing<T: Encode + ?Sized, B: Resizable>(value: &T, buf: &mut B, options: EncodeOptions) -> Result<usize> {
+    let start = buf.len();
+    let mut room = 64;
+    loop {
+        buf.resize_to(start + room);
+        match value.encode(buf, start, options) {
+            Ok(n) => {
+                buf.resize_to(start + n);
+                return Ok(n);
+            },
+            Err(Error::BufferTooSmall) => room *= 2,
+            Err(e) => {
+                buf.resize_to(start);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Encode `value` onto the end of `buf`, growing it until the value fits, and return the
+/// number of bytes added.  `buf` is left as it was if the value can't be encoded.
+#[cfg(feature = "alloc")]
+pub fn encode_to_vec<T: Encode + ?Sized>(value: &T, buf: &mut alloc::vec::Vec<u8>, options: EncodeOptions) -> Result<usize> {
+    encode_growing(value, buf, options)
+}
+
+/// Encode `value` onto the end of `buf`, growing it until the value fits, like
+/// `encode_to_vec`
+#[cfg(feature = "bytes")]
+pub fn encode_to_bytes<T: Encode + ?Sized>(value: &T, buf: &mut bytes::BytesMut, options: EncodeOptions) -> Result<usize> {
+    encode_growing(value, buf, options)
+}
+
+impl<'a> Encode for EncodedElement<'a> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        self.try_write(slice, idx, options)
+    }
+}
+
+impl<'a> Encode for DecodedElement<'a> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        EncodedElement::from(*self).try_write(slice, idx, options)
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        (**self).encode(slice, idx, options)
+    }
+}
+
+/// Implement `Encode` for primitives by converting them to an `EncodedElement`
+macro_rules! impl_encode_primitive {
+    ($($t:ty => $variant:ident as $as:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+                EncodedElement::$variant(*self as $as).try_write(slice, idx, options)
+            }
+        }
+    )*};
+}
+
+impl_encode_primitive!(
+    u8 => UInt as u64, u16 => UInt as u64, u32 => UInt as u64, u64 => UInt as u64, usize => UInt as u64,
+    i8 => Int as i64, i16 => Int as i64, i32 => Int as i64, i64 => Int as i64, isize => Int as i64,
+    f32 => Float as f32, f64 => Double as f64
+);
+
+impl Encode for bool {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        EncodedElement::Bool(*self).try_write(slice, idx, options)
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        EncodedElement::Str(self).try_write(slice, idx, options)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        match self {
+            Some(val) => val.encode(slice, idx, options),
+            None => EncodedElement::Nil.try_write(slice, idx, options),
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_seq(self, slice, idx, options)
+    }
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_seq(self, slice, idx, options)
+    }
+}
+
+/// Implement `Encode` for a tuple, written as an array of its fields
+macro_rules! impl_encode_tuple {
+    ($len:expr; $($name:ident $field:tt),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+                let write_slice = slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?;
+                let mut next = write_len_header(write_slice, &ARRAY_FORMATS, $len)?;
+                $(next += self.$field.encode(write_slice, next, options)?;)+
+                Ok(next)
+            }
+        }
+    };
+}
+
+impl_encode_tuple!(1; A 0);
+impl_encode_tuple!(2; A 0, B 1);
+impl_encode_tuple!(3; A 0, B 1, C 2);
+impl_encode_tuple!(4; A 0, B 1, C 2, D 3);
+impl_encode_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_encode_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_encode_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_encode_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+#[cfg(feature = "alloc")]
+impl Encode for alloc::string::String {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        self.as_str().encode(slice, idx, options)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T: Encode> Encode for alloc::vec::Vec<T> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_seq(self, slice, idx, options)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<K: Encode, V: Encode> Encode for alloc::collections::BTreeMap<K, V> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_map(self, slice, idx, options)
+    }
+}
+
+/// Entries are written in the map's iteration order, which is arbitrary
+#[cfg(feature = "std")]
+impl<K: Encode, V: Encode, S> Encode for std::collections::HashMap<K, V, S> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_map(self, slice, idx, options)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> Encode for heapless::String<N> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        self.as_str().encode(slice, idx, options)
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl<T: Encode, const N: usize> Encode for heapless::Vec<T, N> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_seq(self.as_slice(), slice, idx, options)
+    }
+}
+
+/// Entries are written in insertion order
+#[cfg(feature = "heapless")]
+impl<K: Encode, V: Encode, S, const N: usize> Encode for heapless::IndexMap<K, V, S, N> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        encode_pairs(self.iter(), self.len(), slice, idx, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn fixed_width_int_encode() {
+        let mut buf = [0_u8; 9];
+        assert_eq!(5, EncodedElement::SizedUInt{width: IntWidth::Fixed(4), val: 7}.write_to(&mut buf, 0, false));
+        assert_eq!([0xCE, 0x00, 0x00, 0x00, 0x07], buf[..5]);
+        assert_eq!(3, EncodedElement::SizedInt{width: IntWidth::Fixed(2), val: -2}.write_to(&mut buf, 0, false));
+        assert_eq!([0xD1, 0xFF, 0xFE], buf[..3]);
+        // Values that don't fit in the requested width are not written
+        assert_eq!(0, EncodedElement::SizedInt{width: IntWidth::Fixed(1), val: 300}.write_to(&mut buf, 0, false));
+        assert_eq!(0, EncodedElement::SizedUInt{width: IntWidth::Fixed(0), val: 128}.write_to(&mut buf, 0, false));
+    }
+
+    #[test]
+    fn shortest_int_encode() {
+        // Each side of every boundary between formats
+        let ints: [(i64, &[u8]); 20] = [
+            (i64::MIN, &[0xD3, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            (-2147483649, &[0xD3, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF]),
+            (-2147483648, &[0xD2, 0x80, 0, 0, 0]),
+            (-32769, &[0xD2, 0xFF, 0xFF, 0x7F, 0xFF]),
+            (-32768, &[0xD1, 0x80, 0]),
+            (-129, &[0xD1, 0xFF, 0x7F]),
+            (-128, &[0xD0, 0x80]),
+            (-33, &[0xD0, 0xDF]),
+            (-32, &[0xE0]),
+            (-1, &[0xFF]),
+            (0, &[0x00]),
+            (1, &[0x01]),
+            (127, &[0x7F]),
+            (128, &[0xCC, 0x80]),
+            (255, &[0xCC, 0xFF]),
+            (256, &[0xCD, 0x01, 0x00]),
+            (65535, &[0xCD, 0xFF, 0xFF]),
+            (65536, &[0xCE, 0, 0x01, 0, 0]),
+            (4294967296, &[0xCF, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+            (i64::MAX, &[0xCF, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+        let uints: [(u64, &[u8]); 10] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0xCC, 0x80]),
+            (255, &[0xCC, 0xFF]),
+            (256, &[0xCD, 0x01, 0x00]),
+            (65535, &[0xCD, 0xFF, 0xFF]),
+            (65536, &[0xCE, 0, 0x01, 0, 0]),
+            (4294967295, &[0xCE, 0xFF, 0xFF, 0xFF, 0xFF]),
+            (4294967296, &[0xCF, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+            (u64::MAX, &[0xCF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+        let mut buf = [0_u8; 9];
+        for (val, expected) in ints {
+            assert_eq!(expected.len(), EncodedElement::Int(val).write_to(&mut buf, 0, false), "{}", val);
+            assert_eq!(expected, &buf[..expected.len()], "{}", val);
+            match DecodedElement::from_slice_idx(expected, 0, false) {
+                Some(DecodedElement::Int{val: v, ..}) => assert_eq!(val, v),
+                Some(DecodedElement::UInt{val: v, ..}) => assert_eq!(val as u64, v),
+                other => panic!("{} decoded as {:?}", val, other),
             }
         }
+        for (val, expected) in uints {
+            assert_eq!(expected.len(), EncodedElement::UInt(val).write_to(&mut buf, 0, false), "{}", val);
+            assert_eq!(expected, &buf[..expected.len()], "{}", val);
+            match DecodedElement::from_slice_idx(expected, 0, false) {
+                Some(DecodedElement::Int{val: v, ..}) => assert_eq!(val as i64, v),
+                Some(DecodedElement::UInt{val: v, ..}) => assert_eq!(val, v),
+                other => panic!("{} decoded as {:?}", val, other),
+            }
+        }
+        // Nothing is written if the buffer is too small
+        assert_eq!(0, EncodedElement::Int(65536).write_to(&mut buf[..4], 0, false));
+    }
+
+    #[test]
+    fn length_boundaries() {
+        // The marker each length is written with as (str, bin, ext, array, map)
+        let lengths: [(usize, [u8; 5]); 12] = [
+            (0, [0xA0, 0xC4, 0xC7, 0x90, 0x80]),
+            (1, [0xA1, 0xC4, 0xD4, 0x91, 0x81]),
+            (2, [0xA2, 0xC4, 0xD5, 0x92, 0x82]),
+            (3, [0xA3, 0xC4, 0xC7, 0x93, 0x83]),
+            (15, [0xAF, 0xC4, 0xC7, 0x9F, 0x8F]),
+            (16, [0xB0, 0xC4, 0xD8, 0xDC, 0xDE]),
+            (31, [0xBF, 0xC4, 0xC7, 0xDC, 0xDE]),
+            (32, [0xD9, 0xC4, 0xC7, 0xDC, 0xDE]),
+            (255, [0xD9, 0xC4, 0xC7, 0xDC, 0xDE]),
+            (256, [0xDA, 0xC5, 0xC8, 0xDC, 0xDE]),
+            (65535, [0xDA, 0xC5, 0xC8, 0xDC, 0xDE]),
+            (65536, [0xDB, 0xC6, 0xC9, 0xDD, 0xDF]),
+        ];
+        let data = vec![b'x'; 65536];
+        let nils = vec![EncodedElement::Nil; 65536];
+        let pairs = vec![[EncodedElement::Nil, EncodedElement::Nil]; 65536];
+        let mut buf = vec![0_u8; 2 * 65536 + 6];
+        for (len, markers) in lengths {
+            let elements = [
+                EncodedElement::Str(core::str::from_utf8(&data[..len]).unwrap()),
+                EncodedElement::Bin(&data[..len]),
+                EncodedElement::Ext{exttype: 9, data: &data[..len]},
+                EncodedElement::Array(&nils[..len]),
+                EncodedElement::Map(&pairs[..len]),
+            ];
+            for (el, marker) in elements.into_iter().zip(markers) {
+                let n = el.try_write(&mut buf, 0, EncodeOptions::default()).unwrap();
+                assert_eq!(marker, buf[0], "{} {:02x}", len, marker);
+                let decoded = DecodedElement::from_slice_idx(&buf[..n], 0, false).unwrap();
+                assert_eq!(n, decoded.byte_size(), "{} {:02x}", len, marker);
+                let decoded_len = match decoded {
+                    DecodedElement::Str{val, ..} => val.len(),
+                    DecodedElement::Bin{val, ..} => val.len(),
+                    DecodedElement::Ext{exttype: 9, data, ..} => data.len(),
+                    DecodedElement::Array(a) => a.len(),
+                    DecodedElement::Map(m) => m.len(),
+                    other => panic!("{:?}", other),
+                };
+                assert_eq!(len, decoded_len, "{:02x}", marker);
+                // One byte short fails without panicking
+                assert_eq!(Err(Error::BufferTooSmall), el.try_write(&mut buf[..n-1], 0, EncodeOptions::default()));
+            }
+        }
+        // Lengths this large can't be built in a test, so check the headers alone
+        let mut header = [0_u8; 5];
+        for (formats, marker) in [(&STR_FORMATS, 0xDB), (&BIN_FORMATS, 0xC6), (&EXT_FORMATS, 0xC9), (&ARRAY_FORMATS, 0xDD), (&MAP_FORMATS, 0xDF)] {
+            assert_eq!(Ok(5), write_len_header(&mut header, formats, u32::MAX as usize));
+            assert_eq!([marker, 0xFF, 0xFF, 0xFF, 0xFF], header);
+            #[cfg(target_pointer_width = "64")]
+            assert_eq!(Err(Error::LengthOverflow), write_len_header(&mut header, formats, u32::MAX as usize + 1));
+        }
+    }
+
+    #[test]
+    fn byte_order_round_trip() {
+        let long = [b'x'; 300];
+        let long_str = core::str::from_utf8(&long).unwrap();
+        let elements = [
+            EncodedElement::Nil,
+            EncodedElement::Bool(true),
+            EncodedElement::Int(-100),
+            EncodedElement::Int(-1000),
+            EncodedElement::Int(-100_000),
+            EncodedElement::Int(i64::MIN),
+            EncodedElement::UInt(200),
+            EncodedElement::UInt(1000),
+            EncodedElement::UInt(100_000),
+            EncodedElement::UInt(u64::MAX),
+            EncodedElement::Float(-1.25),
+            EncodedElement::Double(1e100),
+            EncodedElement::Str("hi"),
+            EncodedElement::Str(long_str),
+            EncodedElement::Bin(&long),
+            EncodedElement::Ext{exttype: 3, data: &long},
+            EncodedElement::Ext{exttype: 4, data: &[1, 2, 3, 4]},
+            EncodedElement::Array(&[EncodedElement::UInt(1000), EncodedElement::Str(long_str)]),
+            EncodedElement::Map(&[
+                [EncodedElement::UInt(1000), EncodedElement::Int(-100_000)],
+                [EncodedElement::Int(-1000), EncodedElement::Double(0.5)],
+                [EncodedElement::UInt(u64::MAX), EncodedElement::Float(-1.25)],
+            ]),
+        ];
+        for local_endian_fields in [false, true] {
+            for el in elements {
+                let mut buf = [0_u8; 700];
+                let n = el.write_to(&mut buf, 0, local_endian_fields);
+                assert_ne!(0, n, "{:?}", el);
+                let decoded = DecodedElement::from_slice_idx(&buf[..n], 0, local_endian_fields).unwrap();
+                assert_eq!(n, decoded.byte_size(), "{:?}", el);
+                let mut again = [0_u8; 700];
+                assert_eq!(n, EncodedElement::from(decoded).write_to(&mut again, 0, local_endian_fields), "{:?}", el);
+                assert_eq!(buf[..n], again[..n], "{:?}", el);
+            }
+            // Keys and values inside the map are read back in the same byte order
+            let mut buf = [0_u8; 64];
+            let n = elements[elements.len() - 1].write_to(&mut buf, 0, local_endian_fields);
+            let decoded = DecodedElement::from_slice_idx(&buf[..n], 0, local_endian_fields).unwrap();
+            assert_eq!("{1000: -100000, -1000: 0.5, 18446744073709551615: -1.25}", decoded.to_string());
+        }
+        // Numbers follow the byte order, lengths stay big-endian
+        let mut buf = [0_u8; 3];
+        EncodedElement::UInt(0x1234).write_to(&mut buf, 0, true);
+        assert_eq!(0x1234_u16.to_ne_bytes(), buf[1..3]);
+        let mut buf = [0_u8; 303];
+        EncodedElement::Bin(&long).write_to(&mut buf, 0, true);
+        assert_eq!([0xC5, 0x01, 0x2C], buf[..3]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn float_options() {
+        let write = |el: EncodedElement, options| {
+            let mut buf = [0_u8; 9];
+            let n = el.write_with(&mut buf, 0, options);
+            buf[..n].to_vec()
+        };
+        let narrow = EncodeOptions{narrow_floats: true, ..Default::default()};
+        assert_eq!([0xCA, 0x3F, 0xC0, 0x00, 0x00], write(EncodedElement::Double(1.5), narrow)[..]);
+        assert_eq!(9, write(EncodedElement::Double(0.1), narrow).len());
+        assert_eq!(9, write(EncodedElement::Double(1.5), EncodeOptions::default()).len());
+
+        let ints = EncodeOptions{integral_floats_as_ints: true, ..Default::default()};
+        assert_eq!([0xCD, 0x01, 0x00], write(EncodedElement::Double(256.0), ints)[..]);
+        assert_eq!([0xD0, 0x9C], write(EncodedElement::Float(-100.0), ints)[..]);
+        assert_eq!(0xCB, write(EncodedElement::Double(-0.0), ints)[0]);
+        assert_eq!(0xCB, write(EncodedElement::Double(1e20), ints)[0]);
+        assert_eq!(0xCB, write(EncodedElement::Double(f64::INFINITY), ints)[0]);
+
+        let nan = f64::from_bits(0x7FF8_0000_0000_1234);
+        let canonical = EncodeOptions{canonical_nan: true, ..Default::default()};
+        assert_eq!([0xCB, 0x7F, 0xF8, 0, 0, 0, 0, 0, 0], write(EncodedElement::Double(nan), canonical)[..]);
+        assert_eq!([0xCA, 0x7F, 0xC0, 0, 0], write(EncodedElement::Double(nan), EncodeOptions{narrow_floats: true, ..canonical})[..]);
+        assert_eq!(nan.to_be_bytes(), write(EncodedElement::Double(nan), narrow)[1..]);
+
+        // Options reach elements inside containers, and floats widen again on decode
+        let mut buf = [0_u8; 16];
+        let n = EncodedElement::Array(&[EncodedElement::Double(0.5), EncodedElement::Double(3.0)]).write_with(&mut buf, 0, narrow);
+        assert_eq!([0x92, 0xCA, 0x3F, 0x00, 0x00, 0x00, 0xCA, 0x40, 0x40, 0x00, 0x00], buf[..n]);
+        let DecodedElement::Array(mut a) = DecodedElement::from_slice_idx(&buf, 0, false).unwrap() else { panic!() };
+        assert_eq!(Some(0.5), a.next().unwrap().as_f64());
+        assert_eq!(Some(3.0), a.next().unwrap().as_f64());
+    }
+
+    #[test]
+    fn encode_collections() {
+        let options = EncodeOptions::default();
+        let mut buf = [0_u8; 32];
+        let mut expected = [0_u8; 32];
+        let els = [EncodedElement::UInt(1), EncodedElement::UInt(300), EncodedElement::UInt(3)];
+        let n = EncodedElement::Array(&els).write_to(&mut expected, 0, false);
+        assert_eq!(Ok(n), [1_u32, 300, 3].encode(&mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+        assert_eq!(Ok(n), [1_u64, 300, 3][..].encode(&mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+        assert_eq!(Ok(n), vec![1_u16, 300, 3].encode(&mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+        assert_eq!(Ok(n), encode_seq([1, 300, 3].iter(), &mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+
+        // Tuples are arrays, and None is nil
+        assert_eq!(Ok(5), (-1_i8, "a", None::<u8>).encode(&mut buf, 0, options));
+        assert_eq!([0x93, 0xFF, 0xA1, b'a', 0xC0], buf[..5]);
+
+        let pairs = [[EncodedElement::Str("a"), EncodedElement::Bool(true)], [EncodedElement::Str("b"), EncodedElement::Nil]];
+        let n = EncodedElement::Map(&pairs).write_to(&mut expected, 0, false);
+        let map: std::collections::BTreeMap<&str, Option<bool>> = [("a", Some(true)), ("b", None)].into_iter().collect();
+        assert_eq!(Ok(n), map.encode(&mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+        let map: std::collections::HashMap<String, Option<bool>> = [("a".into(), Some(true))].into_iter().collect();
+        assert_eq!(Ok(4), map.encode(&mut buf, 0, options));
+        assert_eq!([0x81, 0xA1, b'a', 0xC3], buf[..4]);
+        assert_eq!(Ok(n), encode_map([("a", Some(true)), ("b", None)], &mut buf, 0, options));
+        assert_eq!(expected[..n], buf[..n]);
+    }
+
+    #[test]
+    fn encode_to_vec_grows() {
+        let mut buf = vec![0xC0];
+        let long = "x".repeat(300);
+        assert_eq!(Ok(303), encode_to_vec(long.as_str(), &mut buf, EncodeOptions::default()));
+        assert_eq!([0xC0, 0xDA, 0x01, 0x2C], buf[..4]);
+        assert_eq!(304, buf.len());
+        assert_eq!(Err(Error::InvalidData), encode_to_vec(&EncodedElement::Raw(&[]), &mut buf, EncodeOptions::default()));
+        assert_eq!(304, buf.len());
+    }
+
+    #[test]
+    fn encode_seq_wrong_length() {
+        // An iterator that claims to have more items than it yields
+        struct Short(u8);
+        impl Iterator for Short {
+            type Item = u8;
+            fn next(&mut self) -> Option<u8> {
+                self.0 = self.0.checked_sub(1)?;
+                Some(self.0)
+            }
+        }
+        impl ExactSizeIterator for Short {
+            fn len(&self) -> usize { self.0 as usize + 1 }
+        }
+        let mut buf = [0_u8; 8];
+        assert_eq!(Err(Error::InvalidData), encode_seq(Short(2), &mut buf, 0, EncodeOptions::default()));
+        assert_eq!(Err(Error::BufferTooSmall), [1, 2, 3].encode(&mut buf[..3], 0, EncodeOptions::default()));
+    }
+
+    #[test]
+    fn decoded_reencode_identical() {
+        // [uint32 5, int8 -1, fixint 7, int16 1]
+        let src: [u8; 12] = [0x94, 0xCE, 0x00, 0x00, 0x00, 0x05, 0xD0, 0xFF, 0x07, 0xD1, 0x00, 0x01];
+        let mut out = [0_u8; 12];
+        let decoded = DecodedElement::from_slice_idx(&src, 0, false).unwrap();
+        assert_eq!(12, EncodedElement::from(decoded).write_to(&mut out, 0, false));
+        assert_eq!(src, out);
+    }
+
+    #[test]
+    fn decoded_reencode_headers() {
+        // [str 8 "hi", bin 16 [0xAA], ext 8 (5, [0xAA]), fixstr "a", fixext 1 (5, [0xAA])]
+        let src: [u8; 20] = [0x95, 0xD9, 0x02, b'h', b'i', 0xC5, 0x00, 0x01, 0xAA, 0xC7, 0x01, 0x05, 0xAA,
+                             0xA1, b'a', 0xD4, 0x05, 0xAA, 0xC0, 0xC0];
+        let mut out = [0_u8; 18];
+        let decoded = DecodedElement::from_slice_idx(&src, 0, false).unwrap();
+        assert_eq!(18, EncodedElement::from(decoded).write_to(&mut out, 0, false));
+        assert_eq!(src[..18], out);
+        // A length that doesn't fit the width asked for
+        let long = "x".repeat(256);
+        assert_eq!(Err(Error::InvalidData), EncodedElement::SizedStr{header_size: 1, val: &long}.try_write(&mut [0; 300], 0, EncodeOptions::default()));
+        assert_eq!(Err(Error::InvalidData), EncodedElement::SizedExt{header_size: 0, exttype: 1, data: b"abc"}.try_write(&mut out, 0, EncodeOptions::default()));
+    }
+
+    #[test]
+    fn raw_passthrough() {
+        // {fixint 1: [fixint 2, fixint 3]}
+        let src: [u8; 5] = [0x81, 0x01, 0x92, 0x02, 0x03];
+        let decoded = DecodedElement::from_slice_idx(&src, 0, false).unwrap();
+        let value = if let DecodedElement::Map(mut m) = decoded {
+            m.next().unwrap().value
+        } else {
+            panic!()
+        };
+        let sub = value.raw_bytes();
+        assert_eq!([0x92, 0x02, 0x03], sub);
+        // Splice the sub-document into a new array
+        let els = [EncodedElement::Nil, EncodedElement::raw_checked(sub, false).unwrap()];
+        let mut out = [0_u8; 5];
+        assert_eq!(5, EncodedElement::Array(&els).write_to(&mut out, 0, false));
+        assert_eq!([0x92, 0xC0, 0x92, 0x02, 0x03], out);
+        // Truncated or trailing bytes are rejected
+        assert_eq!(None, EncodedElement::raw_checked(&sub[..2], false));
+        assert_eq!(None, EncodedElement::raw_checked(&src[1..], false));
+    }
+}
@@ -0,0 +1,84 @@
+// mod endian
+
+/// The byte order of a field.
+///
+/// MessagePack is big-endian throughout.  Passing `local_endian_fields` to the encoder or
+/// decoder switches the numeric values (16, 32 and 64-bit ints, and floats) to the native
+/// byte order of this machine, so that they can be read in place without swapping.
+/// Everything that describes the layout of the message, such as str, bin and ext lengths
+/// and container counts, stays big-endian in both modes so that a message can always be
+/// walked, even by a reader that has the wrong idea about its byte order.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ByteOrder {
+    /// Network byte order, as MessagePack specifies
+    Big,
+    /// The byte order of the machine running this code
+    Native,
+}
+
+impl ByteOrder {
+    /// The order of numeric values for the given `local_endian_fields` setting
+    pub fn from_local_endian(local_endian_fields: bool) -> Self {
+        if local_endian_fields {
+            Self::Native
+        } else {
+            Self::Big
+        }
+    }
+
+    /// Read a number from the start of `buf[idx..]`, or `None` if it is too short
+    pub fn read<N: Field>(self, buf: &[u8], idx: usize) -> Option<N> {
+        N::read(self, buf.get(idx..)?)
+    }
+
+    /// Get the bytes of `val` in this order
+    pub fn bytes<N: Field>(self, val: N) -> N::Bytes {
+        val.bytes(self)
+    }
+}
+
+/// A number that can be stored in a field
+pub trait Field: Copy {
+    /// The encoded form, a byte array of the number's size
+    type Bytes: AsRef<[u8]>;
+    /// Decode from the start of `buf`, or `None` if it is too short
+    fn read(order: ByteOrder, buf: &[u8]) -> Option<Self>;
+    fn bytes(self, order: ByteOrder) -> Self::Bytes;
+}
+
+macro_rules! impl_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            type Bytes = [u8; core::mem::size_of::<$t>()];
+            fn read(order: ByteOrder, buf: &[u8]) -> Option<Self> {
+                let b = buf.get(..core::mem::size_of::<$t>())?.try_into().ok()?;
+                Some(match order {
+                    ByteOrder::Big => <$t>::from_be_bytes(b),
+                    ByteOrder::Native => <$t>::from_ne_bytes(b),
+                })
+            }
+            fn bytes(self, order: ByteOrder) -> Self::Bytes {
+                match order {
+                    ByteOrder::Big => self.to_be_bytes(),
+                    ByteOrder::Native => self.to_ne_bytes(),
+                }
+            }
+        }
+    )*};
+}
+
+impl_field!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        assert_eq!([0x12, 0x34], ByteOrder::Big.bytes(0x1234_u16));
+        assert_eq!(0x1234_u16.to_ne_bytes(), ByteOrder::Native.bytes(0x1234_u16));
+        assert_eq!(Some(-2_i32), ByteOrder::Big.read(&[0, 0xFF, 0xFF, 0xFF, 0xFE], 1));
+        assert_eq!(None, ByteOrder::Big.read::<i32>(&[0xFF, 0xFF, 0xFF], 0));
+        assert_eq!(Some(1.5_f64), ByteOrder::Native.read(&1.5_f64.to_ne_bytes(), 0));
+    }
+}
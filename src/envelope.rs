@@ -0,0 +1,216 @@
+// mod envelope
+
+use crate::decode::{skip, DecodedElement};
+use crate::encode::{Encode, EncodeOptions};
+use crate::endian::ByteOrder;
+use crate::error::{Error, Result};
+
+/// The bytes every frame begins with
+pub const MAGIC: [u8; 2] = [0x4D, 0x50];
+/// The magic and the payload length that follows it
+pub const HEADER_SIZE: usize = MAGIC.len() + 4;
+/// The header and the CRC-32 after the payload
+pub const OVERHEAD: usize = HEADER_SIZE + 4;
+
+/// CRC-32 (IEEE 802.3) lookup table, for the reflected polynomial
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 used by zlib, Ethernet and PNG
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0_u32, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// Write `value` as a frame, `[magic][len][payload][crc32]`, into `slice` beginning at
+/// `idx`, returning the number of bytes written.
+///
+/// The length and CRC are big-endian, and the CRC covers the length and the payload.
+pub fn write_frame<T: Encode + ?Sized>(value: &T, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+    let frame = slice.get_mut(idx..).ok_or(Error::BufferTooSmall)?;
+    if frame.len() < OVERHEAD {
+        return Err(Error::BufferTooSmall);
+    }
+    let len = value.encode(frame, HEADER_SIZE, options)?;
+    let payload_len = u32::try_from(len).map_err(|_| Error::LengthOverflow)?;
+    let end = HEADER_SIZE + len;
+    let crc_field = frame.get_mut(end..end + 4).ok_or(Error::BufferTooSmall)?;
+    crc_field.fill(0);
+    frame[..MAGIC.len()].copy_from_slice(&MAGIC);
+    frame[MAGIC.len()..HEADER_SIZE].copy_from_slice(&ByteOrder::Big.bytes(payload_len));
+    let crc = crc32(&frame[MAGIC.len()..end]);
+    frame[end..end + 4].copy_from_slice(&ByteOrder::Big.bytes(crc));
+    Ok(end + 4)
+}
+
+/// Finds frames in a byte stream that may have bytes missing, corrupted or added, using a
+/// fixed buffer of `N` bytes and no heap.
+///
+/// Push bytes in with `push`, then call `next_frame` until it returns `None`.  Anything before a
+/// magic is skipped.  A frame whose CRC doesn't match is reported, and the search for the
+/// next magic starts again one byte into it, so a good frame that a corrupted length
+/// swallowed is still found.  Frames that don't fit in `N` bytes are reported the same way.
+pub struct FrameDecoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// The size of the frame returned last, which is dropped on the next call
+    consumed: usize,
+    discarded: usize,
+    local_endian_fields: bool,
+}
+
+impl<const N: usize> FrameDecoder<N> {
+    pub fn new(local_endian_fields: bool) -> Self {
+        Self{buf: [0; N], len: 0, consumed: 0, discarded: 0, local_endian_fields}
+    }
+
+    /// Add bytes from the stream, returning how many there was room for
+    pub fn push(&mut self, input: &[u8]) -> usize {
+        self.release();
+        let n = input.len().min(N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&input[..n]);
+        self.len += n;
+        n
+    }
+
+    /// Get the next frame's payload, `Err` for a frame that is corrupt, or `None` if more
+    /// bytes are needed.
+    ///
+    /// Fails with `Error::BadChecksum` if the CRC doesn't match, `Error::CapacityExceeded`
+    /// if the length is more than the buffer holds, and `Error::InvalidData` if the CRC
+    /// matches but the payload isn't exactly one element.
+    pub fn next_frame(&mut self) -> Option<Result<DecodedElement<'_>>> {
+        self.release();
+        self.find_magic();
+        let payload_len = ByteOrder::Big.read::<u32>(&self.buf[..self.len], MAGIC.len())? as usize;
+        if payload_len > N.saturating_sub(OVERHEAD) {
+            self.drop_front(1);
+            return Some(Err(Error::CapacityExceeded));
+        }
+        let end = HEADER_SIZE + payload_len;
+        let crc = ByteOrder::Big.read::<u32>(&self.buf[..self.len], end)?;
+        if crc != crc32(&self.buf[MAGIC.len()..end]) {
+            self.drop_front(1);
+            return Some(Err(Error::BadChecksum));
+        }
+        self.consumed = end + 4;
+        let payload = &self.buf[HEADER_SIZE..end];
+        Some(match skip(payload, 0, self.local_endian_fields) {
+            Ok(size) if size == payload_len => {
+                DecodedElement::from_slice_idx(payload, 0, self.local_endian_fields).ok_or(Error::InvalidData)
+            },
+            _ => Err(Error::InvalidData)
+        })
+    }
+
+    /// The number of bytes skipped so far while looking for a magic
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// Drop everything before the first magic, keeping a byte at the end that could be
+    /// the start of one
+    fn find_magic(&mut self) {
+        let start = self.buf[..self.len].windows(MAGIC.len()).position(|w| w == MAGIC);
+        let skipped = match start {
+            Some(start) => start,
+            None if self.len > 0 && self.buf[self.len - 1] == MAGIC[0] => self.len - 1,
+            None => self.len,
+        };
+        self.discarded += skipped;
+        self.drop_front(skipped);
+    }
+
+    /// Drop the frame returned last
+    fn release(&mut self) {
+        self.drop_front(self.consumed);
+        self.consumed = 0;
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::EncodedElement;
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn frame_layout() {
+        let mut buf = [0_u8; 16];
+        assert_eq!(14, write_frame("abc", &mut buf, 0, EncodeOptions::default()).unwrap());
+        assert_eq!([0x4D, 0x50, 0, 0, 0, 4, 0xA3, b'a', b'b', b'c'], buf[..10]);
+        assert_eq!(crc32(&buf[2..10]).to_be_bytes(), buf[10..14]);
+        assert_eq!(Err(Error::BufferTooSmall), write_frame("abc", &mut buf[..13], 0, EncodeOptions::default()));
+    }
+
+    #[test]
+    fn resync() {
+        let mut stream = Vec::new();
+        let mut frame = [0_u8; 64];
+        let options = EncodeOptions::default();
+        for value in [EncodedElement::Str("first"), EncodedElement::UInt(300), EncodedElement::Str("third")] {
+            stream.extend_from_slice(b"noise");
+            let n = write_frame(&value, &mut frame, 0, options).unwrap();
+            stream.extend_from_slice(&frame[..n]);
+        }
+        // Corrupt the second payload, and put half a magic in the noise before the third frame
+        stream[5 + 16 + 5 + 7] ^= 0xFF;
+        stream.insert(5 + 16 + 5 + 13 + 2, MAGIC[0]);
+
+        let mut decoder = FrameDecoder::<32>::new(false);
+        let mut results = Vec::new();
+        for piece in stream.chunks(7) {
+            let mut piece = piece;
+            while !piece.is_empty() {
+                let n = decoder.push(piece);
+                piece = &piece[n..];
+                while let Some(result) = decoder.next_frame() {
+                    results.push(result.map(|el| el.to_string()));
+                }
+            }
+        }
+        assert_eq!(vec![Ok("\"first\"".to_string()), Err(Error::BadChecksum), Ok("\"third\"".to_string())], results);
+        assert_eq!(5 + 5 + 12 + 1 + 5, decoder.discarded());
+    }
+
+    #[test]
+    fn oversized_frames() {
+        let mut frame = [0_u8; 64];
+        let n = write_frame(&[7_u8; 20][..], &mut frame, 0, EncodeOptions::default()).unwrap();
+        let mut decoder = FrameDecoder::<16>::new(false);
+        decoder.push(&frame[..n]);
+        assert_eq!(Some(Err(Error::CapacityExceeded)), decoder.next_frame().map(|r| r.map(|_| ())));
+        // A good CRC over something that isn't one element
+        let mut bad = frame;
+        bad[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&[0x01, 0x02]);
+        bad[2..6].copy_from_slice(&2_u32.to_be_bytes());
+        let crc = crc32(&bad[2..8]);
+        bad[8..12].copy_from_slice(&crc.to_be_bytes());
+        let mut decoder = FrameDecoder::<16>::new(false);
+        decoder.push(&bad[..12]);
+        assert_eq!(Some(Err(Error::InvalidData)), decoder.next_frame().map(|r| r.map(|_| ())));
+        assert!(decoder.next_frame().is_none());
+    }
+}
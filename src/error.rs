@@ -0,0 +1,60 @@
+// mod error
+
+use core::fmt;
+
+/// Errors from operations that can fail for more than one reason
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The bytes could not be decoded as MessagePack, or an element can't be encoded the way it asks to be
+    InvalidData,
+    /// The path did not lead to an element
+    PathNotFound,
+    /// The output buffer is too small to hold the result
+    BufferTooSmall,
+    /// Containers are nested more deeply than `decode::MAX_DEPTH`
+    TooDeep,
+    /// A schema document could not be understood
+    InvalidSchema,
+    /// A str, bin, ext, array or map is too long for MessagePack's 32-bit length fields
+    LengthOverflow,
+    /// An element can't be decoded as the requested Rust type: it has another type, an
+    /// integer is out of range, or an array has the wrong number of elements
+    TypeMismatch,
+    /// A decoded str, array or map doesn't fit in the fixed capacity of the type it is
+    /// decoded into
+    CapacityExceeded,
+    /// An RPC response has a msgid that no request is waiting on
+    UnknownMsgid,
+    /// A frame's checksum doesn't match its contents
+    BadChecksum,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidData => write!(f, "invalid MessagePack data"),
+            Self::PathNotFound => write!(f, "no element at the given path"),
+            Self::BufferTooSmall => write!(f, "output buffer is too small"),
+            Self::TooDeep => write!(f, "containers are nested too deeply"),
+            Self::InvalidSchema => write!(f, "invalid schema document"),
+            Self::LengthOverflow => write!(f, "length does not fit in 32 bits"),
+            Self::TypeMismatch => write!(f, "element does not match the requested type"),
+            Self::CapacityExceeded => write!(f, "element does not fit in a fixed-capacity container"),
+            Self::UnknownMsgid => write!(f, "response does not match a pending request"),
+            Self::BadChecksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// For readers and writers: every error is `InvalidData`, with this as the source
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
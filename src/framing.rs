@@ -0,0 +1,362 @@
+// mod framing
+
+use crate::decode::{skip, DecodedElement};
+use crate::encode::{Encode, EncodeOptions};
+use crate::error::{Error, Result};
+
+/// Consistent Overhead Byte Stuffing: frames end in 0x00, which never appears inside them
+#[cfg(feature = "cobs")]
+pub mod cobs {
+    use crate::error::{Error, Result};
+
+    pub const DELIMITER: u8 = 0x00;
+
+    /// The most a message of `len` bytes can take once encoded, with its delimiter
+    pub const fn max_encoded_len(len: usize) -> usize {
+        len + len / 254 + 2
+    }
+
+    /// Encode the message in `buf[..len]` in place, followed by the delimiter.  The rest of
+    /// `buf` is used as working space, so it must hold `max_encoded_len(len)` bytes.
+    /// Returns the encoded length.
+    pub fn encode_in_place(buf: &mut [u8], len: usize) -> Result<usize> {
+        if buf.len() < max_encoded_len(len) {
+            return Err(Error::BufferTooSmall);
+        }
+        // Move the message to the end, so that the output never catches up with the input
+        let start = buf.len() - len;
+        buf.copy_within(..len, start);
+        let mut code_idx = 0;
+        let mut code = 1_u8;
+        let mut next = 1;
+        for r in start..buf.len() {
+            let b = buf[r];
+            if b != 0 {
+                buf[next] = b;
+                next += 1;
+                code += 1;
+            }
+            // A full block ends without a zero, unless it ends the message
+            if b == 0 || (code == 0xFF && r + 1 < buf.len()) {
+                buf[code_idx] = code;
+                code_idx = next;
+                next += 1;
+                code = 1;
+            }
+        }
+        buf[code_idx] = code;
+        buf[next] = DELIMITER;
+        Ok(next + 1)
+    }
+
+    /// Decode a frame in place, without its delimiter, returning the length of the message
+    /// now at the start of `frame`.  Fails with `Error::InvalidData` if it isn't valid COBS.
+    pub fn decode_in_place(frame: &mut [u8]) -> Result<usize> {
+        let mut r = 0;
+        let mut next = 0;
+        while r < frame.len() {
+            let code = frame[r] as usize;
+            if code == 0 || r + code > frame.len() {
+                return Err(Error::InvalidData);
+            }
+            for i in r + 1..r + code {
+                if frame[i] == 0 {
+                    return Err(Error::InvalidData);
+                }
+                frame[next] = frame[i];
+                next += 1;
+            }
+            r += code;
+            if code != 0xFF && r < frame.len() {
+                frame[next] = 0;
+                next += 1;
+            }
+        }
+        Ok(next)
+    }
+}
+
+/// Serial Line IP framing (RFC 1055): frames begin and end with END, and END and ESC inside
+/// them are escaped
+#[cfg(feature = "slip")]
+pub mod slip {
+    use crate::error::{Error, Result};
+
+    pub const END: u8 = 0xC0;
+    pub const ESC: u8 = 0xDB;
+    pub const ESC_END: u8 = 0xDC;
+    pub const ESC_ESC: u8 = 0xDD;
+
+    /// The most a message of `len` bytes can take once encoded, with both ENDs
+    pub const fn max_encoded_len(len: usize) -> usize {
+        2 * len + 2
+    }
+
+    /// Encode the message in `buf[..len]` in place, between two ENDs, returning the encoded
+    /// length.  Fails with `Error::BufferTooSmall` if `buf` can't hold it.
+    pub fn encode_in_place(buf: &mut [u8], len: usize) -> Result<usize> {
+        let escapes = buf.get(..len).ok_or(Error::BufferTooSmall)?.iter().filter(|&&b| b == END || b == ESC).count();
+        let encoded_len = len + escapes + 2;
+        if buf.len() < encoded_len {
+            return Err(Error::BufferTooSmall);
+        }
+        // Work backwards, where the output is always at or past the input
+        let mut next = encoded_len - 1;
+        buf[next] = END;
+        for r in (0..len).rev() {
+            let b = buf[r];
+            let (first, second) = match b {
+                END => (ESC, Some(ESC_END)),
+                ESC => (ESC, Some(ESC_ESC)),
+                _ => (b, None),
+            };
+            if let Some(second) = second {
+                next -= 1;
+                buf[next] = second;
+            }
+            next -= 1;
+            buf[next] = first;
+        }
+        buf[0] = END;
+        Ok(encoded_len)
+    }
+
+    /// Decode a frame in place, without its ENDs, returning the length of the message now
+    /// at the start of `frame`.  Fails with `Error::InvalidData` for a bad escape.
+    pub fn decode_in_place(frame: &mut [u8]) -> Result<usize> {
+        let mut r = 0;
+        let mut next = 0;
+        while r < frame.len() {
+            let (b, size) = match (frame[r], frame.get(r + 1)) {
+                (ESC, Some(&ESC_END)) => (END, 2),
+                (ESC, Some(&ESC_ESC)) => (ESC, 2),
+                (ESC, _) => return Err(Error::InvalidData),
+                (b, _) => (b, 1),
+            };
+            frame[next] = b;
+            next += 1;
+            r += size;
+        }
+        Ok(next)
+    }
+}
+
+/// A byte-stuffing scheme, for links that carry no lengths
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Framing {
+    #[cfg(feature = "cobs")]
+    Cobs,
+    #[cfg(feature = "slip")]
+    Slip,
+}
+
+impl Framing {
+    /// The byte that ends a frame
+    pub fn delimiter(self) -> u8 {
+        match self {
+            #[cfg(feature = "cobs")]
+            Self::Cobs => cobs::DELIMITER,
+            #[cfg(feature = "slip")]
+            Self::Slip => slip::END,
+        }
+    }
+
+    pub fn max_encoded_len(self, len: usize) -> usize {
+        match self {
+            #[cfg(feature = "cobs")]
+            Self::Cobs => cobs::max_encoded_len(len),
+            #[cfg(feature = "slip")]
+            Self::Slip => slip::max_encoded_len(len),
+        }
+    }
+
+    /// Encode the message in `buf[..len]` in place, returning the framed length
+    pub fn encode_in_place(self, buf: &mut [u8], len: usize) -> Result<usize> {
+        match self {
+            #[cfg(feature = "cobs")]
+            Self::Cobs => cobs::encode_in_place(buf, len),
+            #[cfg(feature = "slip")]
+            Self::Slip => slip::encode_in_place(buf, len),
+        }
+    }
+
+    /// Decode a frame without its delimiters in place, returning the message length
+    pub fn decode_in_place(self, frame: &mut [u8]) -> Result<usize> {
+        match self {
+            #[cfg(feature = "cobs")]
+            Self::Cobs => cobs::decode_in_place(frame),
+            #[cfg(feature = "slip")]
+            Self::Slip => slip::decode_in_place(frame),
+        }
+    }
+
+    /// Encode `value` into `slice` and frame it in place, returning the framed length.
+    /// The whole of `slice` is available as working space.
+    pub fn write<T: Encode + ?Sized>(self, value: &T, slice: &mut [u8], options: EncodeOptions) -> Result<usize> {
+        let len = value.encode(slice, 0, options)?;
+        self.encode_in_place(slice, len)
+    }
+}
+
+/// Splits a stuffed byte stream into MessagePack payloads, using a fixed buffer of `N`
+/// bytes and no heap.
+///
+/// Push bytes in with `push`, then call `next_frame` until it returns `None`.  Each frame
+/// is unstuffed in place and must hold exactly one element.  Empty frames, such as the
+/// leading END of SLIP, are skipped.  A frame that is longer than the buffer is dropped
+/// and reported once its delimiter arrives.
+pub struct Deframer<const N: usize> {
+    framing: Framing,
+    buf: [u8; N],
+    len: usize,
+    /// The size of the frame returned last, with its delimiter, dropped on the next call
+    consumed: usize,
+    /// Set while skipping the rest of a frame that didn't fit
+    overflowed: bool,
+    local_endian_fields: bool,
+}
+
+impl<const N: usize> Deframer<N> {
+    pub fn new(framing: Framing, local_endian_fields: bool) -> Self {
+        Self{framing, buf: [0; N], len: 0, consumed: 0, overflowed: false, local_endian_fields}
+    }
+
+    /// Add bytes from the stream, returning how many there was room for
+    pub fn push(&mut self, input: &[u8]) -> usize {
+        self.release();
+        let n = input.len().min(N - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&input[..n]);
+        self.len += n;
+        n
+    }
+
+    /// Get the next payload, `Err` for a frame that is corrupt or too long, or `None` if
+    /// more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<DecodedElement<'_>>> {
+        self.release();
+        loop {
+            let delimiter = self.framing.delimiter();
+            let Some(end) = self.buf[..self.len].iter().position(|&b| b == delimiter) else {
+                if self.len == N {
+                    // Nowhere for the rest of the frame to go: drop what there is
+                    self.overflowed = true;
+                    self.len = 0;
+                }
+                return None;
+            };
+            self.consumed = end + 1;
+            if self.overflowed {
+                self.overflowed = false;
+                return Some(Err(Error::CapacityExceeded));
+            }
+            if end == 0 {
+                self.release();
+                continue;
+            }
+            let len = match self.framing.decode_in_place(&mut self.buf[..end]) {
+                Ok(len) => len,
+                Err(e) => return Some(Err(e)),
+            };
+            let payload = &self.buf[..len];
+            return Some(match skip(payload, 0, self.local_endian_fields) {
+                Ok(size) if size == len => DecodedElement::from_slice_idx(payload, 0, self.local_endian_fields).ok_or(Error::InvalidData),
+                _ => Err(Error::InvalidData)
+            });
+        }
+    }
+
+    /// Drop the frame returned last
+    fn release(&mut self) {
+        self.buf.copy_within(self.consumed..self.len, 0);
+        self.len -= self.consumed;
+        self.consumed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::EncodedElement;
+
+    /// Every framing that is enabled
+    const FRAMINGS: &[Framing] = &[
+        #[cfg(feature = "cobs")]
+        Framing::Cobs,
+        #[cfg(feature = "slip")]
+        Framing::Slip,
+    ];
+
+    #[cfg(feature = "cobs")]
+    #[test]
+    fn cobs_vectors() {
+        // Examples from the COBS paper and its Wikipedia page
+        let cases: [(&[u8], &[u8]); 5] = [
+            (&[0x00], &[0x01, 0x01, 0x00]),
+            (&[0x00, 0x00], &[0x01, 0x01, 0x01, 0x00]),
+            (&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]),
+            (&[0x11, 0x00, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01, 0x01, 0x00]),
+            (&[], &[0x01, 0x00]),
+        ];
+        for (message, encoded) in cases {
+            let mut buf = [0_u8; 16];
+            buf[..message.len()].copy_from_slice(message);
+            let n = cobs::encode_in_place(&mut buf, message.len()).unwrap();
+            assert_eq!(encoded, &buf[..n]);
+            assert_eq!(Ok(message.len()), cobs::decode_in_place(&mut buf[..n - 1]));
+            assert_eq!(message, &buf[..message.len()]);
+        }
+        // A run of 254 non-zero bytes fills a block, which needs no zero after it
+        let mut buf = [0x42_u8; 258];
+        assert_eq!(Ok(256), cobs::encode_in_place(&mut buf, 254));
+        assert_eq!([0xFF, 0x42], buf[..2]);
+        assert_eq!([0x42, 0x00], buf[254..256]);
+        assert_eq!(Ok(254), cobs::decode_in_place(&mut buf[..255]));
+        assert_eq!(Err(Error::BufferTooSmall), cobs::encode_in_place(&mut buf[..255], 254));
+        assert_eq!(Err(Error::InvalidData), cobs::decode_in_place(&mut [0x03, 0x11]));
+    }
+
+    #[cfg(feature = "slip")]
+    #[test]
+    fn slip_escapes() {
+        let mut buf = [0_u8; 16];
+        buf[..4].copy_from_slice(&[0x01, slip::END, slip::ESC, 0x02]);
+        assert_eq!(Ok(8), slip::encode_in_place(&mut buf, 4));
+        assert_eq!([slip::END, 0x01, slip::ESC, slip::ESC_END, slip::ESC, slip::ESC_ESC, 0x02, slip::END], buf[..8]);
+        assert_eq!(Ok(4), slip::decode_in_place(&mut buf[1..7]));
+        assert_eq!([0x01, slip::END, slip::ESC, 0x02], buf[1..5]);
+        assert_eq!(Err(Error::BufferTooSmall), slip::encode_in_place(&mut buf[..7], 4));
+        assert_eq!(Err(Error::InvalidData), slip::decode_in_place(&mut [0x01, slip::ESC]));
+    }
+
+    #[test]
+    fn deframe_stream() {
+        for &framing in FRAMINGS {
+            let mut stream = [0_u8; 128];
+            let mut len = 0;
+            let values = [EncodedElement::UInt(0), EncodedElement::Bin(&[0xC0, 0xDB, 0x00]), EncodedElement::Str("too long for the buffer"),
+                          EncodedElement::Int(-64)];
+            for value in values {
+                len += framing.write(&value, &mut stream[len..], EncodeOptions::default()).unwrap();
+            }
+            // Garbage that unstuffs to something that isn't one element
+            stream[len..len + 3].copy_from_slice(&[0x02, 0x92, framing.delimiter()]);
+            len += 3;
+
+            let mut deframer = Deframer::<16>::new(framing, false);
+            let mut results = Vec::new();
+            for piece in stream[..len].chunks(5) {
+                let mut piece = piece;
+                while !piece.is_empty() {
+                    let n = deframer.push(piece);
+                    piece = &piece[n..];
+                    while let Some(result) = deframer.next_frame() {
+                        results.push(result.map(|el| el.to_string()));
+                    }
+                }
+            }
+            let expected = vec![Ok("0".to_string()), Ok("b\"\\xc0\\xdb\\x00\"".to_string()), Err(Error::CapacityExceeded),
+                                Ok("-64".to_string()), Err(Error::InvalidData)];
+            assert_eq!(expected, results, "{:?}", framing);
+        }
+    }
+}
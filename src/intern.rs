@@ -0,0 +1,224 @@
+// mod intern
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::decode::{walk, DecodedElement, MapDecoder, Visitor};
+use crate::encode::{encode_to_vec, Encode, EncodeOptions, EncodedElement};
+use crate::error::{Error, Result};
+
+/// The ext type of the header that carries the key dictionary
+pub const KEYS_EXT_TYPE: u8 = 101;
+
+/// A value encoded with its repeated map keys replaced by references into a dictionary.
+///
+/// This writes two elements, so it belongs at the top level of a message rather than
+/// inside another value: a header ext whose data is an array of the dictionary's strs,
+/// then the value, where every map key that is a uint is the index of a key in the
+/// dictionary.  Keys that appear only once stay strs.  The most common keys get the
+/// smallest indexes, so up to 128 of them take a single byte each.
+///
+/// Both elements are built once, in `new`, so writing them again, or retrying into a
+/// bigger buffer after `Error::BufferTooSmall`, only copies them.  Read the result back
+/// with `KeyDictionary`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Interned {
+    encoded: Vec<u8>,
+}
+
+impl Interned {
+    /// Encode `value` with `options` and intern its keys.  Since a uint key would be read
+    /// as a reference, a value that has int keys fails with `Error::InvalidData`.
+    pub fn new<T: Encode + ?Sized>(value: &T, options: EncodeOptions) -> Result<Self> {
+        let mut raw = Vec::new();
+        encode_to_vec(value, &mut raw, options)?;
+        let mut counter = KeyCounter{counts: BTreeMap::new(), is_key: false, int_key: false};
+        walk(&raw, &mut counter, options.local_endian_fields)?;
+        if counter.int_key {
+            return Err(Error::InvalidData);
+        }
+        let mut repeated: Vec<(&str, (usize, usize))> = counter.counts.into_iter().filter(|(_, (count, _))| *count > 1).collect();
+        repeated.sort_by_key(|(_, (count, first))| (usize::MAX - count, *first));
+        let keys: Vec<&str> = repeated.iter().map(|(key, _)| *key).collect();
+
+        let mut dictionary = Vec::new();
+        encode_to_vec(&keys, &mut dictionary, options)?;
+        let mut encoded = Vec::new();
+        encode_to_vec(&EncodedElement::Ext{exttype: KEYS_EXT_TYPE, data: &dictionary}, &mut encoded, options)?;
+        let refs = keys.iter().enumerate().map(|(i, key)| (*key, i as u64)).collect();
+        let mut rewriter = KeyRewriter{raw: &raw, out: encoded, refs, is_key: false, options};
+        walk(&raw, &mut rewriter, options.local_endian_fields)?;
+        Ok(Self{encoded: rewriter.out})
+    }
+
+    /// Get the header and the value that follows it, as they are written
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.encoded
+    }
+}
+
+impl Encode for Interned {
+    fn encode(&self, slice: &mut [u8], idx: usize, _options: EncodeOptions) -> Result<usize> {
+        let out = idx.checked_add(self.encoded.len()).and_then(|end| slice.get_mut(idx..end)).ok_or(Error::BufferTooSmall)?;
+        out.copy_from_slice(&self.encoded);
+        Ok(self.encoded.len())
+    }
+}
+
+/// Counts how often each str key appears, and where it first appears
+struct KeyCounter<'a> {
+    counts: BTreeMap<&'a str, (usize, usize)>,
+    is_key: bool,
+    int_key: bool,
+}
+
+impl<'a> Visitor<'a> for KeyCounter<'a> {
+    fn visit_element(&mut self, idx: usize, el: &DecodedElement<'a>) {
+        if core::mem::take(&mut self.is_key) {
+            match el {
+                DecodedElement::Str{val, ..} => self.counts.entry(*val).or_insert((0, idx)).0 += 1,
+                DecodedElement::Int{..} | DecodedElement::UInt{..} => self.int_key = true,
+                _ => {}
+            }
+        }
+    }
+
+    fn map_key(&mut self) {
+        self.is_key = true;
+    }
+}
+
+/// Copies every element, writing a reference in place of each key in the dictionary
+struct KeyRewriter<'a, 'k> {
+    raw: &'a [u8],
+    out: Vec<u8>,
+    refs: BTreeMap<&'k str, u64>,
+    is_key: bool,
+    options: EncodeOptions,
+}
+
+impl<'a> Visitor<'a> for KeyRewriter<'a, '_> {
+    fn visit_element(&mut self, idx: usize, el: &DecodedElement<'a>) {
+        if core::mem::take(&mut self.is_key) {
+            if let DecodedElement::Str{val, ..} = el {
+                if let Some(&reference) = self.refs.get(val) {
+                    let mut buf = [0_u8; 9];
+                    let n = EncodedElement::UInt(reference).try_write(&mut buf, 0, self.options).unwrap_or(0);
+                    self.out.extend_from_slice(&buf[..n]);
+                    return;
+                }
+            }
+        }
+        // Containers only copy their header, since their elements are visited next
+        let size = match el {
+            DecodedElement::Array(a) => 1 + a.header_size() as usize,
+            DecodedElement::Map(m) => 1 + m.header_size() as usize,
+            _ => el.byte_size()
+        };
+        self.out.extend_from_slice(&self.raw[idx..idx + size]);
+    }
+
+    fn map_key(&mut self) {
+        self.is_key = true;
+    }
+}
+
+/// The dictionary from the header written by `Interned`, for turning references back into
+/// keys
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyDictionary<'a> {
+    keys: Vec<&'a str>,
+}
+
+impl<'a> KeyDictionary<'a> {
+    /// Read the header at `idx` in `buf`, returning the dictionary and the index of the
+    /// value that follows it.  Fails with `Error::TypeMismatch` if there is no header
+    /// there, and `Error::InvalidData` if it is truncated or invalid.
+    pub fn read(buf: &'a [u8], idx: usize, local_endian_fields: bool) -> Result<(Self, usize)> {
+        let el = DecodedElement::from_slice_idx(buf, idx, local_endian_fields).ok_or(Error::InvalidData)?;
+        let DecodedElement::Ext{exttype: KEYS_EXT_TYPE, data, ..} = el else {
+            return Err(Error::TypeMismatch);
+        };
+        let Some(DecodedElement::Array(a)) = DecodedElement::from_slice_idx(data, 0, local_endian_fields) else {
+            return Err(Error::InvalidData);
+        };
+        let keys = a.map(|key| match key {
+            DecodedElement::Str{val, ..} => Ok(val),
+            _ => Err(Error::InvalidData)
+        }).collect::<Result<Vec<_>>>()?;
+        if keys.len() != a.len() {
+            return Err(Error::InvalidData);
+        }
+        Ok((Self{keys}, idx + el.byte_size()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Get the key a map key stands for: a str is itself, and a uint is the key at that
+    /// index.  `None` for anything else, or an index past the end of the dictionary.
+    pub fn resolve(&self, key: &DecodedElement<'a>) -> Option<&'a str> {
+        match key {
+            DecodedElement::Str{val, ..} => Some(val),
+            DecodedElement::UInt{val, ..} => self.keys.get(usize::try_from(*val).ok()?).copied(),
+            DecodedElement::Int{val, ..} => self.keys.get(usize::try_from(*val).ok()?).copied(),
+            _ => None
+        }
+    }
+
+    /// Find the value for `key` in a map written by `Interned`
+    pub fn get(&self, mut map: MapDecoder<'a>, key: &str) -> Option<DecodedElement<'a>> {
+        map.find(|kv| self.resolve(&kv.key) == Some(key)).map(|kv| kv.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::Decode;
+
+    #[test]
+    fn records_round_trip() {
+        let records: Vec<_> = (0..100_u32).map(|i| (
+            BTreeMap::from([("timestamp", 1_700_000_000 + i), ("temperature", 20 + i % 5), ("humidity", 40 + i % 7)]),
+            BTreeMap::from([("sensor", "kitchen")])
+        )).collect();
+        let plain = encode_to_vec(&records, &mut Vec::new(), EncodeOptions::default()).unwrap();
+        let interned = Interned::new(&records, EncodeOptions::default()).unwrap();
+        let mut buf = Vec::new();
+        let n = encode_to_vec(&interned, &mut buf, EncodeOptions::default()).unwrap();
+        assert!(n * 2 < plain);
+        assert_eq!(interned.as_bytes(), &buf[..]);
+        // Nothing is written unless all of it fits
+        let mut short = vec![0_u8; n - 1];
+        assert_eq!(Err(Error::BufferTooSmall), interned.encode(&mut short, 0, EncodeOptions::default()));
+        assert!(short.iter().all(|b| *b == 0));
+
+        let (dictionary, idx) = KeyDictionary::read(&buf, 0, false).unwrap();
+        // Ordered by how often each key appears; "sensor" appears as often but later
+        assert_eq!(vec!["humidity", "temperature", "timestamp", "sensor"], dictionary.keys);
+        let DecodedElement::Array(a) = DecodedElement::from_slice_idx(&buf, idx, false).unwrap() else { panic!() };
+        for (i, record) in a.enumerate() {
+            let (fields, tags) = <(DecodedElement, DecodedElement)>::decode(record).unwrap();
+            let (DecodedElement::Map(fields), DecodedElement::Map(tags)) = (fields, tags) else { panic!() };
+            assert_eq!(Ok(20 + i as u32 % 5), u32::decode(dictionary.get(fields, "temperature").unwrap()));
+            assert_eq!("\"kitchen\"", dictionary.get(tags, "sensor").unwrap().to_string());
+            assert!(dictionary.get(fields, "sensor").is_none());
+        }
+    }
+
+    #[test]
+    fn single_keys_and_int_keys() {
+        let buf = Interned::new(&BTreeMap::from([("once", 1)]), EncodeOptions::default()).unwrap().encoded;
+        let (dictionary, idx) = KeyDictionary::read(&buf, 0, false).unwrap();
+        assert!(dictionary.is_empty());
+        assert_eq!(&[0x81, 0xA4, b'o', b'n', b'c', b'e', 0x01], &buf[idx..]);
+        assert_eq!(Err(Error::InvalidData), Interned::new(&BTreeMap::from([(1, 2)]), EncodeOptions::default()));
+        assert_eq!(Err(Error::TypeMismatch), KeyDictionary::read(&[0x90], 0, false).map(|_| ()));
+    }
+}
@@ -1,5 +1,28 @@
-#![feature(int_log)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 pub mod decode;
 pub mod encode;
-pub mod bytesize;
\ No newline at end of file
+pub mod bytesize;
+pub mod chunked;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod compress;
+pub mod display;
+pub mod edit;
+pub mod endian;
+pub mod envelope;
+pub mod error;
+#[cfg(any(feature = "cobs", feature = "slip"))]
+pub mod framing;
+#[cfg(feature = "alloc")]
+pub mod schema;
+#[cfg(feature = "alloc")]
+pub mod rpc;
+#[cfg(feature = "alloc")]
+pub mod intern;
+#[cfg(feature = "bytes")]
+pub mod shared;
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub mod asyncio;
\ No newline at end of file
@@ -0,0 +1,422 @@
+// mod rpc
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::decode::{from_slice, skip, ArrayDecoder, Decode, DecodedElement};
+use crate::encode::{encode_to_vec, Encode, EncodeOptions, EncodedElement};
+use crate::error::{Error, Result};
+
+/// The type of a request, `[0, msgid, method, params]`
+pub const REQUEST: u8 = 0;
+/// The type of a response, `[1, msgid, error, result]`
+pub const RESPONSE: u8 = 1;
+/// The type of a notification, `[2, method, params]`
+pub const NOTIFICATION: u8 = 2;
+
+/// A call that expects a response with the same msgid.
+///
+/// `params` is written as an array, so a tuple or slice of the arguments will do.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Request<'a, P> {
+    pub msgid: u32,
+    pub method: &'a str,
+    pub params: P,
+}
+
+/// The answer to a request.  `error` is nil if the call succeeded, and `result` is nil if
+/// it didn't.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Response<E, R> {
+    pub msgid: u32,
+    pub error: E,
+    pub result: R,
+}
+
+/// A call that expects no response
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Notification<'a, P> {
+    pub method: &'a str,
+    pub params: P,
+}
+
+impl<P: Encode> Encode for Request<'_, P> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        (REQUEST, self.msgid, self.method, &self.params).encode(slice, idx, options)
+    }
+}
+
+impl<E: Encode, R: Encode> Encode for Response<E, R> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        (RESPONSE, self.msgid, &self.error, &self.result).encode(slice, idx, options)
+    }
+}
+
+impl<P: Encode> Encode for Notification<'_, P> {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        (NOTIFICATION, self.method, &self.params).encode(slice, idx, options)
+    }
+}
+
+/// Fail with `Error::TypeMismatch` unless a message has the type it should
+fn check_type(found: u8, expected: u8) -> Result<()> {
+    if found == expected {
+        Ok(())
+    } else {
+        Err(Error::TypeMismatch)
+    }
+}
+
+impl<'a, P: Decode<'a>> Decode<'a> for Request<'a, P> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let (msgtype, msgid, method, params) = <(u8, u32, &str, P)>::decode(el)?;
+        check_type(msgtype, REQUEST)?;
+        Ok(Self{msgid, method, params})
+    }
+}
+
+impl<'a, E: Decode<'a>, R: Decode<'a>> Decode<'a> for Response<E, R> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let (msgtype, msgid, error, result) = <(u8, u32, E, R)>::decode(el)?;
+        check_type(msgtype, RESPONSE)?;
+        Ok(Self{msgid, error, result})
+    }
+}
+
+impl<'a, P: Decode<'a>> Decode<'a> for Notification<'a, P> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let (msgtype, method, params) = <(u8, &str, P)>::decode(el)?;
+        check_type(msgtype, NOTIFICATION)?;
+        Ok(Self{method, params})
+    }
+}
+
+impl<'a> Response<DecodedElement<'a>, DecodedElement<'a>> {
+    /// Get the result, or the error if there is one
+    pub fn into_result(self) -> core::result::Result<DecodedElement<'a>, DecodedElement<'a>> {
+        match self.error {
+            DecodedElement::Nil{..} => Ok(self.result),
+            error => Err(error)
+        }
+    }
+}
+
+/// Any message, as it was received
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Message<'a> {
+    Request(Request<'a, ArrayDecoder<'a>>),
+    Response(Response<DecodedElement<'a>, DecodedElement<'a>>),
+    Notification(Notification<'a, ArrayDecoder<'a>>),
+}
+
+impl<'a> Decode<'a> for Message<'a> {
+    fn decode(el: DecodedElement<'a>) -> Result<Self> {
+        let mut items = ArrayDecoder::decode(el)?;
+        match items.next().map(u8::decode) {
+            Some(Ok(REQUEST)) => Request::decode(el).map(Self::Request),
+            Some(Ok(RESPONSE)) => Response::decode(el).map(Self::Response),
+            Some(Ok(NOTIFICATION)) => Notification::decode(el).map(Self::Notification),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+}
+
+/// Encode a value into a new buffer
+fn encode_owned<T: Encode + ?Sized>(value: &T, options: EncodeOptions) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    encode_to_vec(value, &mut buf, options)?;
+    Ok(buf)
+}
+
+/// Sends requests and matches the responses to them by msgid.
+///
+/// The client does no I/O of its own, so it works over any transport: requests are
+/// encoded into a buffer for the caller to send, and the caller passes each message that
+/// arrives to `receive`.  Responses can arrive in any order.
+pub struct Client {
+    next_msgid: u32,
+    /// Requests waiting on a response, and the responses that haven't been taken yet
+    pending: BTreeMap<u32, Option<Vec<u8>>>,
+    options: EncodeOptions,
+}
+
+impl Client {
+    pub fn new(options: EncodeOptions) -> Self {
+        Self{next_msgid: 0, pending: BTreeMap::new(), options}
+    }
+
+    /// Encode a request onto the end of `out` and return its msgid
+    pub fn request<P: Encode>(&mut self, method: &str, params: P, out: &mut Vec<u8>) -> Result<u32> {
+        let mut msgid = self.next_msgid;
+        while self.pending.contains_key(&msgid) {
+            msgid = msgid.wrapping_add(1);
+        }
+        encode_to_vec(&Request{msgid, method, params}, out, self.options)?;
+        self.next_msgid = msgid.wrapping_add(1);
+        self.pending.insert(msgid, None);
+        Ok(msgid)
+    }
+
+    /// Encode a notification onto the end of `out`, returning the number of bytes added
+    pub fn notify<P: Encode>(&self, method: &str, params: P, out: &mut Vec<u8>) -> Result<usize> {
+        encode_to_vec(&Notification{method, params}, out, self.options)
+    }
+
+    /// Take a message from the server.  A response is kept until `take_response` asks for
+    /// it, and its msgid is returned.  Requests and notifications from the server are left
+    /// for the caller to handle, and give `None`.
+    ///
+    /// Fails with `Error::UnknownMsgid` if a response doesn't match a request that is still
+    /// waiting for one.
+    pub fn receive(&mut self, message: &[u8]) -> Result<Option<u32>> {
+        let local_endian_fields = self.options.local_endian_fields;
+        let Message::Response(response) = from_slice(message, local_endian_fields)? else { return Ok(None) };
+        match self.pending.get_mut(&response.msgid) {
+            Some(slot @ None) => {
+                let len = skip(message, 0, local_endian_fields)?;
+                *slot = Some(message[..len].to_vec());
+                Ok(Some(response.msgid))
+            },
+            _ => Err(Error::UnknownMsgid)
+        }
+    }
+
+    /// Take the response to a request once it has arrived, ready to decode as a `Response`
+    pub fn take_response(&mut self, msgid: u32) -> Option<Vec<u8>> {
+        match self.pending.get(&msgid) {
+            Some(Some(_)) => self.pending.remove(&msgid).flatten(),
+            _ => None
+        }
+    }
+
+    /// Stop waiting for the response to a request, so that it fails with
+    /// `Error::UnknownMsgid` if it does arrive
+    pub fn cancel(&mut self, msgid: u32) {
+        self.pending.remove(&msgid);
+    }
+}
+
+/// A method handler with its types erased, which returns the encoded result or error
+type Handler = Box<dyn FnMut(DecodedElement<'_>, EncodeOptions) -> core::result::Result<Vec<u8>, Vec<u8>>>;
+
+/// Calls the registered handler for each request and notification, and encodes the
+/// responses.
+///
+/// Like `Client`, the server does no I/O: the caller passes each message to `handle` and
+/// sends whatever it writes.  Unknown methods and params that don't decode as the handler
+/// expects are answered with an error string.
+pub struct Server {
+    handlers: BTreeMap<String, Handler>,
+    options: EncodeOptions,
+}
+
+impl Server {
+    pub fn new(options: EncodeOptions) -> Self {
+        Self{handlers: BTreeMap::new(), options}
+    }
+
+    /// Call `handler` for `method`, replacing any handler already registered for it.  The
+    /// params array is decoded as a `P`, usually a tuple of the arguments.  `Ok` is sent as
+    /// the result and `Err` as the error.
+    pub fn register<P, R, E, F>(&mut self, method: &str, mut handler: F)
+    where P: for<'a> Decode<'a>, R: Encode, E: Encode, F: FnMut(P) -> core::result::Result<R, E> + 'static
+    {
+        let handler = move |params: DecodedElement<'_>, options: EncodeOptions| {
+            let params = P::decode(params).map_err(|e| error_message(format!("invalid params: {}", e), options))?;
+            let encoded = match handler(params) {
+                Ok(result) => encode_owned(&result, options).map(Ok),
+                Err(error) => encode_owned(&error, options).map(Err),
+            };
+            encoded.unwrap_or_else(|e| Err(error_message(format!("can't encode response: {}", e), options)))
+        };
+        self.handlers.insert(method.into(), Box::new(handler));
+    }
+
+    /// Handle one message from a client, encoding the response to a request onto the end of
+    /// `out`.  Returns the number of bytes added, which is 0 for a notification.
+    ///
+    /// A request whose msgid can be read but which doesn't decode otherwise, such as one
+    /// with params that aren't an array, is answered with an error rather than left for the
+    /// client to wait on forever.
+    pub fn handle(&mut self, message: &[u8], out: &mut Vec<u8>) -> Result<usize> {
+        let local_endian_fields = self.options.local_endian_fields;
+        let message = match from_slice(message, local_endian_fields) {
+            Ok(m) => m,
+            Err(e) => return match request_msgid(message, local_endian_fields) {
+                Some(msgid) => {
+                    let error = error_message(format!("invalid params: {}", e), self.options);
+                    encode_to_vec(&Response{msgid, error: EncodedElement::Raw(&error), result: EncodedElement::Nil}, out, self.options)
+                },
+                None => Err(e)
+            }
+        };
+        match message {
+            Message::Request(request) => {
+                let outcome = self.call(request.method, request.params);
+                let response = match &outcome {
+                    Ok(result) => Response{msgid: request.msgid, error: EncodedElement::Nil, result: EncodedElement::Raw(result)},
+                    Err(error) => Response{msgid: request.msgid, error: EncodedElement::Raw(error), result: EncodedElement::Nil},
+                };
+                encode_to_vec(&response, out, self.options)
+            },
+            Message::Notification(notification) => {
+                // There's nobody to tell if a notification fails
+                let _ = self.call(notification.method, notification.params);
+                Ok(0)
+            },
+            Message::Response(_) => Err(Error::TypeMismatch)
+        }
+    }
+
+    fn call(&mut self, method: &str, params: ArrayDecoder) -> core::result::Result<Vec<u8>, Vec<u8>> {
+        match self.handlers.get_mut(method) {
+            Some(handler) => handler(DecodedElement::Array(params), self.options),
+            None => Err(error_message(format!("unknown method: {}", method), self.options))
+        }
+    }
+}
+
+/// Get the msgid of a message that begins like a request, `[0, msgid, ..]`
+fn request_msgid(message: &[u8], local_endian_fields: bool) -> Option<u32> {
+    let DecodedElement::Array(mut items) = DecodedElement::from_slice_idx(message, 0, local_endian_fields)? else { return None };
+    match (items.next().map(u8::decode), items.next().map(u32::decode)) {
+        (Some(Ok(REQUEST)), Some(Ok(msgid))) => Some(msgid),
+        _ => None
+    }
+}
+
+/// Encode an error string for a response
+fn error_message(message: String, options: EncodeOptions) -> Vec<u8> {
+    encode_owned(message.as_str(), options).expect("short strings always encode")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{probe, Probe};
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Read one message from a pipe, keeping any bytes after it in `buf`
+    fn read_message(r: &mut impl Read, buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+        loop {
+            if let Ok(Probe::Complete(len)) = probe(buf, false) {
+                return Some(buf.drain(..len).collect());
+            }
+            let mut chunk = [0_u8; 7];
+            let n = r.read(&mut chunk).unwrap();
+            if n == 0 {
+                return None;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    #[test]
+    fn client_server_pipes() {
+        let (mut server_in, mut client_out) = std::io::pipe().unwrap();
+        let (mut client_in, mut server_out) = std::io::pipe().unwrap();
+        let logged = Arc::new(AtomicUsize::new(0));
+        let counter = logged.clone();
+        let server = std::thread::spawn(move || {
+            let mut server = Server::new(EncodeOptions::default());
+            server.register("add", |(a, b): (i64, i64)| Ok::<_, &str>(a + b));
+            server.register("div", |(a, b): (i64, i64)| a.checked_div(b).ok_or("divide by zero"));
+            server.register("log", move |(_,): (String,)| Ok::<_, &str>(counter.fetch_add(1, Ordering::SeqCst)));
+            let (mut buf, mut out) = (Vec::new(), Vec::new());
+            while let Some(message) = read_message(&mut server_in, &mut buf) {
+                out.clear();
+                server.handle(&message, &mut out).unwrap();
+                server_out.write_all(&out).unwrap();
+            }
+        });
+
+        let mut client = Client::new(EncodeOptions::default());
+        let mut out = Vec::new();
+        client.notify("log", ("starting",), &mut out).unwrap();
+        let add = client.request("add", (2, 40), &mut out).unwrap();
+        let div = client.request("div", (1, 0), &mut out).unwrap();
+        let missing = client.request("mul", (2, 3), &mut out).unwrap();
+        let bad = client.request("add", ("2", 3), &mut out).unwrap();
+        client_out.write_all(&out).unwrap();
+        drop(client_out);
+
+        let mut buf = Vec::new();
+        for _ in 0..4 {
+            let message = read_message(&mut client_in, &mut buf).unwrap();
+            assert!(client.receive(&message).unwrap().is_some());
+        }
+        let result = |client: &mut Client, msgid| {
+            let response = client.take_response(msgid).unwrap();
+            let response: Response<DecodedElement, DecodedElement> = from_slice(&response, false).unwrap();
+            match response.into_result() {
+                Ok(el) => el.to_string(),
+                Err(el) => format!("error {}", el),
+            }
+        };
+        assert_eq!("error \"unknown method: mul\"", result(&mut client, missing));
+        assert_eq!("error \"divide by zero\"", result(&mut client, div));
+        assert_eq!("42", result(&mut client, add));
+        assert_eq!("error \"invalid params: element does not match the requested type\"", result(&mut client, bad));
+        assert_eq!(None, client.take_response(add));
+        server.join().unwrap();
+        assert_eq!(1, logged.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let mut server = Server::new(EncodeOptions::default());
+        server.register("add", |(a, b): (i64, i64)| Ok::<_, &str>(a + b));
+        let mut out = Vec::new();
+        // Params that aren't an array still get a response with the msgid
+        let request = encode_owned(&(REQUEST, 9_u32, "add", 5), EncodeOptions::default()).unwrap();
+        let n = server.handle(&request, &mut out).unwrap();
+        assert_eq!(out.len(), n);
+        let response: Response<&str, Option<u8>> = from_slice(&out, false).unwrap();
+        assert_eq!(9, response.msgid);
+        assert_eq!(None, response.result);
+        assert!(response.error.starts_with("invalid params: "), "{}", response.error);
+        // Without a msgid there is nobody to answer
+        out.clear();
+        let request = encode_owned(&(REQUEST, "add"), EncodeOptions::default()).unwrap();
+        assert_eq!(Err(Error::TypeMismatch), server.handle(&request, &mut out));
+        let notification = encode_owned(&(NOTIFICATION, "add", 5), EncodeOptions::default()).unwrap();
+        assert_eq!(Err(Error::TypeMismatch), server.handle(&notification, &mut out));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn match_by_msgid() {
+        let mut client = Client::new(EncodeOptions::default());
+        let mut out = Vec::new();
+        let first = client.request("a", [0_u8; 0], &mut out).unwrap();
+        let second = client.request("b", [0_u8; 0], &mut out).unwrap();
+        let requests: Vec<Message> = {
+            let mut idx = 0;
+            (0..2).map(|_| {
+                let message = from_slice(&out[idx..], false).unwrap();
+                idx = skip(&out, idx, false).unwrap();
+                message
+            }).collect()
+        };
+        let Message::Request(request) = requests[1] else { panic!() };
+        assert_eq!((second, "b", 0), (request.msgid, request.method, request.params.len()));
+
+        // The second response arrives first
+        let response = |msgid, result| encode_owned(&Response{msgid, error: EncodedElement::Nil, result}, EncodeOptions::default()).unwrap();
+        assert_eq!(Ok(Some(second)), client.receive(&response(second, "two")));
+        assert_eq!(Ok(Some(first)), client.receive(&response(first, "one")));
+        let decoded = client.take_response(first).unwrap();
+        assert_eq!(Ok(Response{msgid: first, error: None::<u8>, result: "one"}), from_slice(&decoded, false));
+        assert_eq!(Err(Error::UnknownMsgid), client.receive(&response(first, "again")));
+        client.cancel(second);
+        assert_eq!(None, client.take_response(second));
+        // Messages that aren't responses are left for the caller
+        let notification = encode_owned(&Notification{method: "event", params: (1,)}, EncodeOptions::default()).unwrap();
+        assert_eq!(Ok(None), client.receive(&notification));
+    }
+}
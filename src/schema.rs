@@ -0,0 +1,462 @@
+// mod schema
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use super::decode::*;
+
+/// A value that an `Schema::Enum` accepts
+#[derive(Clone, PartialEq, Debug)]
+pub enum Literal {
+    Int(i128),
+    Str(String),
+}
+
+/// An entry of a `Schema::Map`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Field {
+    pub key: String,
+    pub required: bool,
+    pub schema: Schema,
+}
+
+/// The expected shape of a MessagePack message.
+///
+/// Bounds that are `None` are not checked.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Schema {
+    /// Anything at all
+    Any,
+    Nil,
+    Bool,
+    /// A signed or unsigned integer within the given range
+    Int{min: Option<i128>, max: Option<i128>},
+    /// A float, double or integer
+    Number,
+    /// A string whose length in bytes is within the given range
+    Str{min_len: Option<usize>, max_len: Option<usize>},
+    Bin{min_len: Option<usize>, max_len: Option<usize>},
+    /// One of a fixed set of integers or strings
+    Enum(Vec<Literal>),
+    /// An array whose elements all match `items`
+    Array{items: Box<Schema>, min_len: Option<usize>, max_len: Option<usize>},
+    /// A map with string keys.  Keys not listed in `fields` are only allowed if `additional` is set.
+    Map{fields: Vec<Field>, additional: bool},
+    /// Anything matching at least one of the schemas
+    AnyOf(Vec<Schema>),
+}
+
+/// Why an element did not match its schema
+#[derive(Clone, PartialEq, Debug)]
+pub enum Violation {
+    /// The element could not be decoded
+    Invalid,
+    WrongType{expected: &'static str, found: &'static str},
+    OutOfRange,
+    TooShort,
+    TooLong,
+    NotInEnum,
+    MissingKey(String),
+    UnexpectedKey(String),
+    /// None of the schemas of an `AnyOf` matched
+    NoMatch,
+}
+
+/// A violation, along with the path to the element it was found at, such as `$.items[3].name`
+#[derive(Clone, PartialEq, Debug)]
+pub struct ValidationError {
+    pub path: String,
+    pub violation: Violation,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.path)?;
+        match &self.violation {
+            Violation::Invalid => write!(f, "invalid MessagePack data"),
+            Violation::WrongType{expected, found} => write!(f, "expected {}, found {}", expected, found),
+            Violation::OutOfRange => write!(f, "value out of range"),
+            Violation::TooShort => write!(f, "too short"),
+            Violation::TooLong => write!(f, "too long"),
+            Violation::NotInEnum => write!(f, "not one of the allowed values"),
+            Violation::MissingKey(k) => write!(f, "missing required key \"{}\"", k),
+            Violation::UnexpectedKey(k) => write!(f, "unexpected key \"{}\"", k),
+            Violation::NoMatch => write!(f, "does not match any of the allowed schemas"),
+        }
+    }
+}
+
+/// Check a length against optional bounds
+fn check_len(len: usize, min_len: Option<usize>, max_len: Option<usize>) -> Option<Violation> {
+    if min_len.is_some_and(|m| len < m) {
+        Some(Violation::TooShort)
+    } else if max_len.is_some_and(|m| len > m) {
+        Some(Violation::TooLong)
+    } else {
+        None
+    }
+}
+
+/// Record a violation found at `path`
+fn fail(errors: &mut Vec<ValidationError>, path: &str, violation: Violation) {
+    errors.push(ValidationError{path: path.into(), violation});
+}
+
+/// Get the value of an integer element, if it is one
+fn int_value(el: &DecodedElement) -> Option<i128> {
+    match el {
+        DecodedElement::Int{val, ..} => Some(*val as i128),
+        DecodedElement::UInt{val, ..} => Some(*val as i128),
+        _ => None
+    }
+}
+
+impl Schema {
+    /// Check a decoded element against this schema, returning every violation found
+    pub fn validate(&self, el: DecodedElement) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let mut path = String::from("$");
+        self.validate_at(el, &mut path, &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Validate `el`, which is found at `path`, adding any violations to `errors`.
+    ///
+    /// This only recurses as deeply as the schema itself is nested, however deep the data is.
+    fn validate_at(&self, el: DecodedElement, path: &mut String, errors: &mut Vec<ValidationError>) {
+        match (self, &el) {
+            (Self::Any, _) | (Self::Nil, DecodedElement::Nil{..}) | (Self::Bool, DecodedElement::Bool{..}) => (),
+            (Self::Int{min, max}, DecodedElement::Int{..} | DecodedElement::UInt{..}) => {
+                let val = int_value(&el).unwrap_or_default();
+                if min.is_some_and(|m| val < m) || max.is_some_and(|m| val > m) {
+                    fail(errors, path, Violation::OutOfRange);
+                }
+            },
+            (Self::Number, DecodedElement::Float{..} | DecodedElement::Double{..} | DecodedElement::Int{..} | DecodedElement::UInt{..}) => (),
+            (Self::Str{min_len, max_len}, DecodedElement::Str{val, ..}) => {
+                if let Some(v) = check_len(val.len(), *min_len, *max_len) {
+                    fail(errors, path, v);
+                }
+            },
+            (Self::Bin{min_len, max_len}, DecodedElement::Bin{val, ..}) => {
+                if let Some(v) = check_len(val.len(), *min_len, *max_len) {
+                    fail(errors, path, v);
+                }
+            },
+            (Self::Enum(values), _) => {
+                let found = values.iter().any(|lit| match (lit, &el) {
+                    (Literal::Int(i), _) => int_value(&el) == Some(*i),
+                    (Literal::Str(s), DecodedElement::Str{val, ..}) => s == val,
+                    _ => false
+                });
+                if !found {
+                    fail(errors, path, Violation::NotInEnum);
+                }
+            },
+            (Self::Array{items, min_len, max_len}, DecodedElement::Array(a)) => {
+                let mut a = *a;
+                a.reset();
+                if let Some(v) = check_len(a.len(), *min_len, *max_len) {
+                    fail(errors, path, v);
+                }
+                if !el.is_complete() {
+                    fail(errors, path, Violation::Invalid);
+                    return;
+                }
+                let base = path.len();
+                let len = a.len();
+                let mut count = 0;
+                for (i, item) in a.enumerate() {
+                    path.push_str(&format!("[{}]", i));
+                    items.validate_at(item, path, errors);
+                    path.truncate(base);
+                    count += 1;
+                }
+                // `is_complete` doesn't check strings, so the iterator can still stop early
+                // at one that isn't valid UTF-8
+                if count < len {
+                    fail(errors, path, Violation::Invalid);
+                }
+            },
+            (Self::Map{fields, additional}, DecodedElement::Map(m)) => {
+                let mut m = *m;
+                m.reset();
+                if !el.is_complete() {
+                    fail(errors, path, Violation::Invalid);
+                    return;
+                }
+                let len = m.len();
+                let mut count = 0;
+                let mut seen = Vec::with_capacity(fields.len());
+                for kv in m {
+                    count += 1;
+                    let key = match kv.key {
+                        DecodedElement::Str{val, ..} => val,
+                        other => {
+                            fail(errors, path, Violation::WrongType{expected: "str", found: other.type_name()});
+                            continue;
+                        }
+                    };
+                    let base = path.len();
+                    path.push('.');
+                    path.push_str(key);
+                    if let Some(field) = fields.iter().find(|f| f.key == key) {
+                        seen.push(key);
+                        field.schema.validate_at(kv.value, path, errors);
+                    } else if !additional {
+                        path.truncate(base);
+                        fail(errors, path, Violation::UnexpectedKey(key.into()));
+                    }
+                    path.truncate(base);
+                }
+                if count < len {
+                    fail(errors, path, Violation::Invalid);
+                    return;
+                }
+                for field in fields.iter().filter(|f| f.required && !seen.contains(&f.key.as_str())) {
+                    fail(errors, path, Violation::MissingKey(field.key.clone()));
+                }
+            },
+            (Self::AnyOf(schemas), _) => {
+                let mut scratch = Vec::new();
+                let matched = schemas.iter().any(|s| {
+                    scratch.clear();
+                    s.validate_at(el, path, &mut scratch);
+                    scratch.is_empty()
+                });
+                if !matched {
+                    fail(errors, path, Violation::NoMatch);
+                }
+            },
+            (_, _) => fail(errors, path, Violation::WrongType{expected: self.type_name(), found: el.type_name()}),
+        }
+    }
+
+    /// Get the name of the MessagePack type this schema expects
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Any => "any",
+            Self::Nil => "nil",
+            Self::Bool => "bool",
+            Self::Int{..} => "int",
+            Self::Number => "number",
+            Self::Str{..} => "str",
+            Self::Bin{..} => "bin",
+            Self::Enum(_) => "enum",
+            Self::Array{..} => "array",
+            Self::Map{..} => "map",
+            Self::AnyOf(_) => "any of",
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+mod json {
+    use super::*;
+    use crate::error::*;
+    use serde_json::Value;
+
+    /// Read an optional non-negative integer keyword
+    fn get_usize(obj: &serde_json::Map<String, Value>, key: &str) -> Result<Option<usize>> {
+        match obj.get(key) {
+            None => Ok(None),
+            Some(v) => v.as_u64().map(|n| Some(n as usize)).ok_or(Error::InvalidSchema),
+        }
+    }
+
+    /// Read an optional integer keyword
+    fn get_int(obj: &serde_json::Map<String, Value>, key: &str) -> Result<Option<i128>> {
+        match obj.get(key) {
+            None => Ok(None),
+            Some(v) => v.as_i64().map(|n| n as i128)
+                .or_else(|| v.as_u64().map(|n| n as i128))
+                .map(Some)
+                .ok_or(Error::InvalidSchema),
+        }
+    }
+
+    impl Schema {
+        /// Load a schema from a JSON-Schema-like document.
+        ///
+        /// This understands `type` (`null`, `boolean`, `integer`, `number`, `string`,
+        /// `binary`, `array` or `object`), `minimum`, `maximum`, `minLength`, `maxLength`,
+        /// `minItems`, `maxItems`, `items`, `properties`, `required`,
+        /// `additionalProperties`, `enum` and `anyOf`.  Other keywords are ignored.
+        pub fn from_json(doc: &str) -> Result<Self> {
+            let value: Value = serde_json::from_str(doc).map_err(|_| Error::InvalidSchema)?;
+            Self::from_json_value(&value)
+        }
+
+        /// Load a schema from an already parsed JSON document
+        pub fn from_json_value(value: &Value) -> Result<Self> {
+            let obj = match value {
+                Value::Object(o) => o,
+                Value::Bool(true) => return Ok(Self::Any),
+                _ => return Err(Error::InvalidSchema),
+            };
+            if let Some(values) = obj.get("enum") {
+                let values = values.as_array().ok_or(Error::InvalidSchema)?;
+                let literals = values.iter().map(|v| match v {
+                    Value::String(s) => Ok(Literal::Str(s.clone())),
+                    Value::Number(n) => n.as_i64().map(|i| i as i128)
+                        .or_else(|| n.as_u64().map(|i| i as i128))
+                        .map(Literal::Int)
+                        .ok_or(Error::InvalidSchema),
+                    _ => Err(Error::InvalidSchema),
+                }).collect::<Result<Vec<_>>>()?;
+                return Ok(Self::Enum(literals));
+            }
+            if let Some(schemas) = obj.get("anyOf") {
+                let schemas = schemas.as_array().ok_or(Error::InvalidSchema)?;
+                return Ok(Self::AnyOf(schemas.iter().map(Self::from_json_value).collect::<Result<_>>()?));
+            }
+            let t = match obj.get("type") {
+                None => return Ok(Self::Any),
+                Some(t) => t.as_str().ok_or(Error::InvalidSchema)?,
+            };
+            Ok(match t {
+                "null" => Self::Nil,
+                "boolean" => Self::Bool,
+                "integer" => Self::Int{min: get_int(obj, "minimum")?, max: get_int(obj, "maximum")?},
+                "number" => Self::Number,
+                "string" => Self::Str{min_len: get_usize(obj, "minLength")?, max_len: get_usize(obj, "maxLength")?},
+                "binary" => Self::Bin{min_len: get_usize(obj, "minLength")?, max_len: get_usize(obj, "maxLength")?},
+                "array" => {
+                    let items = match obj.get("items") {
+                        Some(i) => Self::from_json_value(i)?,
+                        None => Self::Any,
+                    };
+                    Self::Array{items: Box::new(items), min_len: get_usize(obj, "minItems")?, max_len: get_usize(obj, "maxItems")?}
+                },
+                "object" => {
+                    let required = match obj.get("required") {
+                        Some(r) => r.as_array().ok_or(Error::InvalidSchema)?
+                            .iter()
+                            .map(|k| k.as_str().ok_or(Error::InvalidSchema))
+                            .collect::<Result<Vec<_>>>()?,
+                        None => Vec::new(),
+                    };
+                    let mut fields = Vec::new();
+                    if let Some(props) = obj.get("properties") {
+                        for (key, schema) in props.as_object().ok_or(Error::InvalidSchema)? {
+                            fields.push(Field{
+                                key: key.clone(),
+                                required: required.contains(&key.as_str()),
+                                schema: Self::from_json_value(schema)?,
+                            });
+                        }
+                    }
+                    // Required keys without properties of their own can hold anything
+                    for key in required {
+                        if !fields.iter().any(|f| f.key == key) {
+                            fields.push(Field{key: key.into(), required: true, schema: Self::Any});
+                        }
+                    }
+                    let additional = obj.get("additionalProperties").and_then(Value::as_bool).unwrap_or(true);
+                    Self::Map{fields, additional}
+                },
+                _ => return Err(Error::InvalidSchema),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn user_schema() -> Schema {
+        Schema::Map{
+            fields: vec![
+                Field{key: "id".into(), required: true, schema: Schema::Int{min: Some(0), max: None}},
+                Field{key: "name".into(), required: true, schema: Schema::Str{min_len: Some(1), max_len: Some(8)}},
+                Field{key: "tags".into(), required: false, schema: Schema::Array{
+                    items: Box::new(Schema::Enum(vec![Literal::Str("a".into()), Literal::Str("b".into())])),
+                    min_len: None,
+                    max_len: None,
+                }},
+            ],
+            additional: false,
+        }
+    }
+
+    #[test]
+    fn valid_message() {
+        // {"id": 7, "name": "bob"}
+        let t = [0x82, 0xA2, b'i', b'd', 0x07, 0xA4, b'n', b'a', b'm', b'e', 0xA3, b'b', b'o', b'b'];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        assert_eq!(Ok(()), user_schema().validate(el));
+    }
+
+    #[test]
+    fn invalid_message() {
+        // {"id": -1, "tags": ["a", "c"], "x": nil}
+        let t = [0x83, 0xA2, b'i', b'd', 0xFF, 0xA4, b't', b'a', b'g', b's', 0x92, 0xA1, b'a', 0xA1, b'c', 0xA1, b'x', 0xC0];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        let errors = user_schema().validate(el).unwrap_err();
+        assert_eq!(vec![
+            ValidationError{path: "$.id".into(), violation: Violation::OutOfRange},
+            ValidationError{path: "$.tags[1]".into(), violation: Violation::NotInEnum},
+            ValidationError{path: "$".into(), violation: Violation::UnexpectedKey("x".into())},
+            ValidationError{path: "$".into(), violation: Violation::MissingKey("name".into())},
+        ], errors);
+    }
+
+    #[test]
+    fn bad_utf8_inside() {
+        // [1, "\xFF"] against an array of ints: the str stops the iterator early
+        let t = [0x92, 0x01, 0xA1, 0xFF];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        let ints = Schema::Array{items: Box::new(Schema::Int{min: None, max: None}), min_len: None, max_len: None};
+        assert_eq!(Err(vec![ValidationError{path: "$".into(), violation: Violation::Invalid}]), ints.validate(el));
+        // {"id": 7, "name": "\xFF"}
+        let t = [0x82, 0xA2, b'i', b'd', 0x07, 0xA4, b'n', b'a', b'm', b'e', 0xA1, 0xFF];
+        let el = DecodedElement::from_slice_idx(&t, 0, false).unwrap();
+        assert_eq!(Err(vec![ValidationError{path: "$".into(), violation: Violation::Invalid}]), user_schema().validate(el));
+    }
+
+    #[test]
+    fn wrong_type() {
+        let el = DecodedElement::from_slice_idx(&[0xC3], 0, false).unwrap();
+        let errors = user_schema().validate(el).unwrap_err();
+        assert_eq!(Violation::WrongType{expected: "map", found: "bool"}, errors[0].violation);
+        let nullable = Schema::AnyOf(vec![Schema::Nil, Schema::Bool]);
+        assert_eq!(Ok(()), nullable.validate(el));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn from_json() {
+        let doc = r#"{
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer", "minimum": 0},
+                "name": {"type": "string", "minLength": 1, "maxLength": 8},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}}
+            },
+            "required": ["id", "name"],
+            "additionalProperties": false
+        }"#;
+        let schema = Schema::from_json(doc).unwrap();
+        if let (Schema::Map{fields: mut a, additional: x}, Schema::Map{fields: mut b, additional: y}) = (schema, user_schema()) {
+            // JSON objects don't keep their order
+            a.sort_by(|f, g| f.key.cmp(&g.key));
+            b.sort_by(|f, g| f.key.cmp(&g.key));
+            assert_eq!(a, b);
+            assert_eq!(x, y);
+        } else {
+            panic!()
+        }
+        assert_eq!(Err(crate::error::Error::InvalidSchema), Schema::from_json(r#"{"type": "thing"}"#));
+        let schema = Schema::from_json(r#"{"type": "object", "required": ["x"]}"#).unwrap();
+        let el = DecodedElement::from_slice_idx(&[0x80], 0, false).unwrap();
+        assert_eq!(Violation::MissingKey("x".into()), schema.validate(el).unwrap_err()[0].violation);
+    }
+}
@@ -0,0 +1,147 @@
+// mod shared
+
+use alloc::vec::Vec;
+
+use bytes::Bytes;
+
+use crate::decode::{DecodedElement, MAX_DEPTH};
+use crate::encode::{encode_map, encode_seq, Encode, EncodeOptions, EncodedElement};
+use crate::error::{Error, Result};
+
+/// A decoded element that owns its contents, without copying them out of the input.
+///
+/// Str, bin and ext data are `Bytes` slices of the buffer the element was decoded from,
+/// so they share its allocation and keep it alive after the buffer itself is dropped.
+/// Containers are decoded up front into `Vec`s.  Integers lose the width they were
+/// decoded with, so re-encoding writes them in their shortest form.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SharedElement {
+    Nil,
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+    /// UTF-8, checked when it was decoded
+    Str(Bytes),
+    Bin(Bytes),
+    Float(f32),
+    Double(f64),
+    Ext{exttype: u8, data: Bytes},
+    Array(Vec<SharedElement>),
+    Map(Vec<(SharedElement, SharedElement)>),
+}
+
+impl SharedElement {
+    /// Decode the element at `idx` in `buf`.  Fails with `Error::InvalidData` if it is
+    /// truncated or invalid, and `Error::TooDeep` if it is nested more deeply than
+    /// `decode::MAX_DEPTH`.
+    pub fn from_bytes_idx(buf: &Bytes, idx: usize, local_endian_fields: bool) -> Result<Self> {
+        let el = DecodedElement::from_slice_idx(buf, idx, local_endian_fields).ok_or(Error::InvalidData)?;
+        Self::from_element(buf, el, 0)
+    }
+
+    fn from_element(buf: &Bytes, el: DecodedElement, depth: usize) -> Result<Self> {
+        Ok(match el {
+            DecodedElement::Nil{..} => Self::Nil,
+            DecodedElement::Int{val, ..} => Self::Int(val),
+            DecodedElement::UInt{val, ..} => Self::UInt(val),
+            DecodedElement::Bool{val: b, ..} => Self::Bool(b),
+            DecodedElement::Str{val, ..} => Self::Str(buf.slice_ref(val.as_bytes())),
+            DecodedElement::Bin{val, ..} => Self::Bin(buf.slice_ref(val)),
+            DecodedElement::Float{val: f, ..} => Self::Float(f),
+            DecodedElement::Double{val: d, ..} => Self::Double(d),
+            DecodedElement::Ext{exttype, data, ..} => Self::Ext{exttype, data: buf.slice_ref(data)},
+            DecodedElement::Array(a) => {
+                if depth == MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                let items = a.map(|item| Self::from_element(buf, item, depth + 1)).collect::<Result<Vec<_>>>()?;
+                if items.len() != a.len() {
+                    return Err(Error::InvalidData);
+                }
+                Self::Array(items)
+            },
+            DecodedElement::Map(m) => {
+                if depth == MAX_DEPTH {
+                    return Err(Error::TooDeep);
+                }
+                let pairs = m.map(|kv| Ok((Self::from_element(buf, kv.key, depth + 1)?, Self::from_element(buf, kv.value, depth + 1)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                if pairs.len() != m.len() {
+                    return Err(Error::InvalidData);
+                }
+                Self::Map(pairs)
+            },
+        })
+    }
+
+    /// Get a str as a `&str`, or `None` for any other type
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(b) => core::str::from_utf8(b).ok(),
+            _ => None
+        }
+    }
+}
+
+impl Encode for SharedElement {
+    fn encode(&self, slice: &mut [u8], idx: usize, options: EncodeOptions) -> Result<usize> {
+        match self {
+            Self::Nil => EncodedElement::Nil.try_write(slice, idx, options),
+            Self::Int(i) => EncodedElement::Int(*i).try_write(slice, idx, options),
+            Self::UInt(u) => EncodedElement::UInt(*u).try_write(slice, idx, options),
+            Self::Bool(b) => EncodedElement::Bool(*b).try_write(slice, idx, options),
+            Self::Str(_) => EncodedElement::Str(self.as_str().ok_or(Error::InvalidData)?).try_write(slice, idx, options),
+            Self::Bin(b) => EncodedElement::Bin(b).try_write(slice, idx, options),
+            Self::Float(f) => EncodedElement::Float(*f).try_write(slice, idx, options),
+            Self::Double(d) => EncodedElement::Double(*d).try_write(slice, idx, options),
+            Self::Ext{exttype, data} => EncodedElement::Ext{exttype: *exttype, data}.try_write(slice, idx, options),
+            Self::Array(items) => encode_seq(items, slice, idx, options),
+            Self::Map(pairs) => encode_map(pairs.iter().map(|(k, v)| (k, v)), slice, idx, options),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::encode_to_bytes;
+    use bytes::BytesMut;
+
+    #[test]
+    fn shares_input() {
+        let mut buf = BytesMut::new();
+        let value = (7, "hello", EncodedElement::Bin(&[1, 2, 3]), [("k", EncodedElement::Ext{exttype: 4, data: b"xy"})]);
+        encode_to_bytes(&value, &mut buf, EncodeOptions::default()).unwrap();
+        let first = buf.len();
+        // Longer than the first attempt at growing the buffer
+        let long = "x".repeat(100);
+        encode_to_bytes(long.as_str(), &mut buf, EncodeOptions::default()).unwrap();
+        assert_eq!(first + 102, buf.len());
+
+        let frozen = buf.freeze();
+        let el = SharedElement::from_bytes_idx(&frozen, 0, false).unwrap();
+        let SharedElement::Array(items) = &el else { panic!() };
+        assert_eq!(SharedElement::Int(7), items[0]);
+        assert_eq!(Some("hello"), items[1].as_str());
+        let SharedElement::Bin(bin) = &items[2] else { panic!() };
+        // The decoded bin points into the input rather than a copy of it
+        assert_eq!(frozen.as_ptr() as usize + 10, bin.as_ptr() as usize);
+        let long_el = SharedElement::from_bytes_idx(&frozen, first, false).unwrap();
+        drop(frozen);
+        assert_eq!(Some(long.as_str()), long_el.as_str());
+
+        // Re-encoding gives back the same bytes, since every int was already shortest
+        let mut again = BytesMut::new();
+        encode_to_bytes(&el, &mut again, EncodeOptions::default()).unwrap();
+        let mut expected = BytesMut::new();
+        encode_to_bytes(&value, &mut expected, EncodeOptions::default()).unwrap();
+        assert_eq!(expected, again);
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(Err(Error::InvalidData), SharedElement::from_bytes_idx(&Bytes::from_static(&[0x92, 0x01]), 0, false));
+        let deep = Bytes::from(vec![0x91_u8; MAX_DEPTH + 1]);
+        assert_eq!(Err(Error::TooDeep), SharedElement::from_bytes_idx(&deep, 0, false));
+    }
+}
@@ -0,0 +1,255 @@
+// Decode the fixtures written by msgpack-python and @msgpack/msgpack, and encode them again.
+//
+// See tests/fixtures/README.md for what each fixture holds and how it was produced.
+
+use minimp::decode::*;
+use minimp::encode::*;
+
+fn fixture(lib: &str, name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{}/{}.msgpack", env!("CARGO_MANIFEST_DIR"), lib, name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+/// The fixtures each library has
+const PYTHON: [&str; 5] = ["scalars", "strings", "containers", "ext", "single_float"];
+const JS: [&str; 4] = ["scalars", "strings", "containers", "ext"];
+
+/// Encode `el` the way an application holding the same values would, rather than copying
+/// the widths it was decoded with
+fn encode_value(el: DecodedElement) -> Vec<u8> {
+    let mut children = Vec::new();
+    let new = match el {
+        DecodedElement::Nil{..} => EncodedElement::Nil,
+        DecodedElement::Bool{val: b, ..} => EncodedElement::Bool(b),
+        DecodedElement::Int{val, ..} => EncodedElement::Int(val),
+        DecodedElement::UInt{val, ..} => EncodedElement::UInt(val),
+        DecodedElement::Float{val: f, ..} => EncodedElement::Float(f),
+        DecodedElement::Double{val: d, ..} => EncodedElement::Double(d),
+        DecodedElement::Str{val, ..} => EncodedElement::Str(val),
+        DecodedElement::Bin{val, ..} => EncodedElement::Bin(val),
+        DecodedElement::Ext{exttype, data, ..} => EncodedElement::Ext{exttype, data},
+        DecodedElement::Array(a) => {
+            children.extend(a.map(encode_value));
+            return encode_container(el, &children);
+        },
+        DecodedElement::Map(m) => {
+            for kv in m {
+                children.push(encode_value(kv.key));
+                children.push(encode_value(kv.value));
+            }
+            return encode_container(el, &children);
+        },
+    };
+    let mut buf = vec![0_u8; el.byte_size() + 16];
+    let n = new.write_to(&mut buf, 0, false);
+    buf.truncate(n);
+    buf
+}
+
+/// Encode a container around children that are already encoded
+fn encode_container(el: DecodedElement, children: &[Vec<u8>]) -> Vec<u8> {
+    let raw: Vec<EncodedElement> = children.iter().map(|c| EncodedElement::Raw(c)).collect();
+    let pairs: Vec<[EncodedElement; 2]> = raw.chunks_exact(2).map(|kv| [kv[0], kv[1]]).collect();
+    let new = match el {
+        DecodedElement::Array(_) => EncodedElement::Array(&raw),
+        _ => EncodedElement::Map(&pairs),
+    };
+    let mut buf = vec![0_u8; children.iter().map(Vec::len).sum::<usize>() + 5];
+    let n = new.write_to(&mut buf, 0, false);
+    buf.truncate(n);
+    buf
+}
+
+const COMMON_INTS: &str = "null, true, false, 0, 1, 127, 128, 255, 256, 65535, 65536, 4294967295, 4294967296";
+const NEGATIVE_INTS: &str = "-1, -32, -33, -128, -129, -32768, -32769, -2147483648, -2147483649";
+
+#[test]
+fn decode_scalars() {
+    let python = fixture("python", "scalars");
+    let python_el = DecodedElement::from_slice_idx(&python, 0, false).unwrap();
+    assert_eq!(format!("[{}, 18446744073709551615, {}, -9223372036854775808, 1.5, -0.25, 1e300]", COMMON_INTS, NEGATIVE_INTS),
+               python_el.to_string());
+}
+
+fn check_strings(lib: &str) {
+    let x = |n| "x".repeat(n);
+    let expected = format!(r#"["", "a", "{}", "{}", "{}", "{}", "héllo", b"", b"\x00\xff", b"{}"]"#,
+                           x(31), x(32), x(255), x(256), x(256));
+    let buf = fixture(lib, "strings");
+    let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+    assert_eq!(expected, el.to_string(), "{}", lib);
+}
+
+#[test]
+fn decode_strings() {
+    check_strings("python");
+}
+
+fn expected_containers() -> String {
+    let list = |n| (0..n).map(|i: i32| i.to_string()).collect::<Vec<_>>().join(", ");
+    let object = |n| (0..n).map(|i: i32| format!("\"{}\": {}", i, i)).collect::<Vec<_>>().join(", ");
+    let record = r#"{"id": 7, "name": "widget", "tags": ["a", "b"], "dims": {"w": 1.5, "h": 2.0}, "data": b"\x01\x02", "none": null}"#;
+    format!("[[], {{}}, [{}], [{}], {{{}}}, {{{}}}, {}]", list(15), list(16), object(15), object(16), record)
+}
+
+#[test]
+fn decode_containers() {
+    let buf = fixture("python", "containers");
+    let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+    assert_eq!(expected_containers(), el.to_string());
+}
+
+fn check_ext(lib: &str) {
+    let x = |n| "78".repeat(n);
+    // Ext types are signed on the wire, so the timestamp type -1 shows up as 255
+    let expected = format!("[ext(5, 01), ext(5, 6162), ext(1, 616263), ext(2, 61626364), ext(3, {}), ext(4, {}), ext(6, ), ext(7, {}), ext(255, 00000001)]",
+                           x(8), x(16), x(300));
+    let buf = fixture(lib, "ext");
+    let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+    assert_eq!(expected, el.to_string(), "{}", lib);
+}
+
+#[test]
+fn decode_ext() {
+    check_ext("python");
+}
+
+#[test]
+fn decode_single_float() {
+    let buf = fixture("python", "single_float");
+    let DecodedElement::Array(items) = DecodedElement::from_slice_idx(&buf, 0, false).unwrap() else { panic!() };
+    let floats: Vec<_> = items.map(|el| match el {
+        DecodedElement::Float{val, ..} => Some(val),
+        _ => None
+    }).collect();
+    assert_eq!(vec![Some(1.5), Some(0.1), Some(-2.0)], floats);
+}
+
+fn check_single_messages(lib: &str, names: &[&str]) {
+    for name in names {
+        let buf = fixture(lib, name);
+        assert_eq!(Ok(buf.len()), skip(&buf, 0, false), "{}/{}", lib, name);
+    }
+}
+
+#[test]
+fn fixtures_are_single_messages() {
+    check_single_messages("python", &PYTHON);
+}
+
+fn check_reencode_decoded(lib: &str, names: &[&str]) {
+    // Decoded elements keep their widths, so they come out exactly as they went in
+    for name in names {
+        let buf = fixture(lib, name);
+        let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+        let mut out = vec![0_u8; buf.len()];
+        assert_eq!(buf.len(), EncodedElement::from(el).write_to(&mut out, 0, false), "{}/{}", lib, name);
+        assert_eq!(buf, out, "{}/{}", lib, name);
+    }
+}
+
+#[test]
+fn reencode_decoded() {
+    check_reencode_decoded("python", &PYTHON);
+}
+
+fn check_reencode_values(lib: &str, names: &[&str]) {
+    // Both libraries use the shortest form for every value, as we do, so encoding the
+    // decoded values reproduces each element of every fixture
+    for name in names {
+        let buf = fixture(lib, name);
+        let top = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+        let DecodedElement::Array(items) = top else { panic!("{}/{} is not an array", lib, name) };
+        let mut idx = 1 + items.header_size() as usize;
+        for (i, item) in items.enumerate() {
+            let size = item.byte_size();
+            assert_eq!(buf[idx..idx + size], encode_value(item), "{}/{} element {}", lib, name, i);
+            idx += size;
+        }
+    }
+}
+
+#[test]
+fn reencode_values() {
+    check_reencode_values("python", &PYTHON);
+}
+
+#[test]
+fn intentional_differences() {
+    // Python only sends float 32 when asked to, and then the value is rounded: it is not
+    // the double the sender started with
+    let python = fixture("python", "single_float");
+    let DecodedElement::Array(mut items) = DecodedElement::from_slice_idx(&python, 0, false).unwrap() else { panic!() };
+    let Some(DecodedElement::Float{val: f, ..}) = items.nth(1) else { panic!() };
+    assert_ne!(0.1_f64, f as f64);
+}
+
+/// The @msgpack/msgpack fixtures aren't checked in: run `generate.mjs` with the real
+/// library, then `cargo test --test compat -- --ignored`
+mod js {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn decode_scalars() {
+        let js = fixture("js", "scalars");
+        // JavaScript numbers stop at 2^53, and integral floats are written as ints
+        let el = DecodedElement::from_slice_idx(&js, 0, false).unwrap();
+        assert_eq!(format!("[{}, 9007199254740991, {}, 1.5, -0.25, 1e300, 2]", COMMON_INTS, NEGATIVE_INTS), el.to_string());
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn decode_strings() {
+        check_strings("js");
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn decode_containers() {
+        // The only difference from Python is that the integral float 2.0 arrives as an int
+        let buf = fixture("js", "containers");
+        let el = DecodedElement::from_slice_idx(&buf, 0, false).unwrap();
+        assert_eq!(expected_containers().replace("\"h\": 2.0", "\"h\": 2"), el.to_string());
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn decode_ext() {
+        check_ext("js");
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn fixtures_are_single_messages() {
+        check_single_messages("js", &JS);
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn reencode_decoded() {
+        check_reencode_decoded("js", &JS);
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn reencode_values() {
+        check_reencode_values("js", &JS);
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/js, written by generate.mjs"]
+    fn intentional_differences() {
+        // JavaScript has no separate float type, so an integral double arrives as an int.  We
+        // write the double we were given: both decode to the number 2, but the bytes differ.
+        let js = fixture("js", "scalars");
+        assert_eq!(0x02, js[js.len() - 1]);
+        let mut buf = [0_u8; 9];
+        assert_eq!(9, EncodedElement::Double(2.0).write_to(&mut buf, 0, false));
+        assert_eq!(0xCB, buf[0]);
+        // With integral floats written as ints, we send the same byte as JavaScript
+        let ints = EncodeOptions{integral_floats_as_ints: true, ..Default::default()};
+        assert_eq!(1, EncodedElement::Double(2.0).write_with(&mut buf, 0, ints));
+        assert_eq!(0x02, buf[0]);
+    }
+}
@@ -0,0 +1,46 @@
+// Write the @msgpack/msgpack fixtures into tests/fixtures/js.
+//
+// Run from the repository root with @msgpack/msgpack 3.x installed:
+//
+//     node tests/fixtures/generate.mjs
+
+import { writeFileSync, mkdirSync } from "node:fs";
+import { encode, ExtData } from "@msgpack/msgpack";
+
+const out = new URL("./js/", import.meta.url);
+const x = (n) => "x".repeat(n);
+const bytes = (n) => new Uint8Array(n).fill(0x78);
+const range = (n) => [...Array(n).keys()];
+const object = (n) => Object.fromEntries(range(n).map((i) => [String(i), i]));
+
+const fixtures = {
+  scalars: [
+    null, true, false,
+    0, 1, 127, 128, 255, 256, 65535, 65536, 4294967295, 4294967296, Number.MAX_SAFE_INTEGER,
+    -1, -32, -33, -128, -129, -32768, -32769, -2147483648, -2147483649,
+    1.5, -0.25, 1e300, 2.0,
+  ],
+  strings: [
+    "", "a", x(31), x(32), x(255), x(256), "héllo",
+    new Uint8Array(0), Uint8Array.of(0x00, 0xff), bytes(256),
+  ],
+  containers: [
+    [], {},
+    range(15), range(16),
+    object(15), object(16),
+    { id: 7, name: "widget", tags: ["a", "b"], dims: { w: 1.5, h: 2.0 },
+      data: Uint8Array.of(1, 2), none: null },
+  ],
+  ext: [
+    new ExtData(5, Uint8Array.of(1)), new ExtData(5, Uint8Array.of(0x61, 0x62)),
+    new ExtData(1, Uint8Array.of(0x61, 0x62, 0x63)), new ExtData(2, Uint8Array.of(0x61, 0x62, 0x63, 0x64)),
+    new ExtData(3, bytes(8)), new ExtData(4, bytes(16)),
+    new ExtData(6, new Uint8Array(0)), new ExtData(7, bytes(300)),
+    new Date(1000),
+  ],
+};
+
+mkdirSync(out, { recursive: true });
+for (const [name, value] of Object.entries(fixtures)) {
+  writeFileSync(new URL(`${name}.msgpack`, out), encode(value));
+}
@@ -0,0 +1,47 @@
+"""Write the msgpack-python fixtures into tests/fixtures/python.
+
+Run from the repository root with msgpack >= 1.0 installed:
+
+    python3 tests/fixtures/generate.py
+"""
+
+import os
+import msgpack
+
+OUT = os.path.join(os.path.dirname(__file__), "python")
+
+FIXTURES = {
+    "scalars": [
+        None, True, False,
+        0, 1, 127, 128, 255, 256, 65535, 65536, 4294967295, 4294967296, 18446744073709551615,
+        -1, -32, -33, -128, -129, -32768, -32769, -2147483648, -2147483649, -9223372036854775808,
+        1.5, -0.25, 1e300,
+    ],
+    "strings": [
+        "", "a", "x" * 31, "x" * 32, "x" * 255, "x" * 256, "héllo",
+        b"", b"\x00\xff", b"x" * 256,
+    ],
+    "containers": [
+        [], {},
+        list(range(15)), list(range(16)),
+        {str(i): i for i in range(15)}, {str(i): i for i in range(16)},
+        {"id": 7, "name": "widget", "tags": ["a", "b"], "dims": {"w": 1.5, "h": 2.0},
+         "data": b"\x01\x02", "none": None},
+    ],
+    "ext": [
+        msgpack.ExtType(5, b"\x01"), msgpack.ExtType(5, b"ab"), msgpack.ExtType(1, b"abc"),
+        msgpack.ExtType(2, b"abcd"), msgpack.ExtType(3, b"x" * 8), msgpack.ExtType(4, b"x" * 16),
+        msgpack.ExtType(6, b""), msgpack.ExtType(7, b"x" * 300),
+        msgpack.Timestamp(1),
+    ],
+}
+
+# Python floats are doubles; this is what a peer that opts into single floats sends
+SINGLE_FLOATS = [1.5, 0.1, -2.0]
+
+os.makedirs(OUT, exist_ok=True)
+for name, value in FIXTURES.items():
+    with open(os.path.join(OUT, name + ".msgpack"), "wb") as f:
+        f.write(msgpack.packb(value))
+with open(os.path.join(OUT, "single_float.msgpack"), "wb") as f:
+    f.write(msgpack.packb(SINGLE_FLOATS, use_single_float=True))